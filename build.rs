@@ -0,0 +1,48 @@
+//! Compiles every shader in `shaders/` to SPIR-V in `OUT_DIR`, along with a
+//! `shaders.rs` listing that embeds them into the binary.
+
+use std::fmt::Write;
+use std::path::Path;
+use std::process::exit;
+
+#[path = "src/gfx/glsl.rs"]
+mod glsl;
+
+fn main() {
+    println!("cargo:rerun-if-changed=shaders");
+    println!("cargo:rerun-if-changed=src/gfx/glsl.rs");
+
+    let out_dir = std::env::var("OUT_DIR").unwrap();
+    let mut paths = std::fs::read_dir("shaders")
+        .expect("Failed to read `shaders/`.")
+        .map(|e| e.unwrap().path())
+        .filter(|p| glsl::shader_stage(p).is_some())
+        .collect::<Vec<_>>();
+    paths.sort();
+
+    let mut failed = false;
+    let mut listing = String::from("&[\n");
+    for path in &paths {
+        println!("cargo:rerun-if-changed={}", path.display());
+
+        let name = path.file_name().unwrap().to_str().unwrap();
+        match glsl::compile_glsl(path) {
+            Ok(bytecode) => {
+                let output = Path::new(&out_dir).join(format!("{}.spv", name));
+                std::fs::write(&output, bytecode).unwrap();
+                writeln!(listing, "    ({:?}, include_bytes!({:?})),", name, output).unwrap();
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                failed = true;
+            }
+        }
+    }
+    listing.push(']');
+
+    if failed {
+        exit(1);
+    }
+
+    std::fs::write(Path::new(&out_dir).join("shaders.rs"), listing).unwrap();
+}
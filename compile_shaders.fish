@@ -1,3 +0,0 @@
-#!/sbin/fish
-
-cd ./shaders/ && for f in ./*; glslc $f -o ../compiled/$f.spv; end && cd ..
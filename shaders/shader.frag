@@ -1,9 +1,19 @@
 #version 450
 
+// The combined image sampler at binding 1.
+layout(binding = 1) uniform texture2D texImage;
+layout(binding = 1) uniform sampler texSampler;
+
+layout(push_constant) uniform ObjectConstants {
+    mat4 model;
+    vec4 tint;
+} object;
+
 layout(location = 0) in vec3 fragColor;
+layout(location = 1) in vec2 fragTexCoord;
 
 layout(location = 0) out vec4 outColor;
 
 void main() {
-    outColor = vec4(fragColor, 1.0);
+    outColor = texture(sampler2D(texImage, texSampler), fragTexCoord) * object.tint;
 }
@@ -1,5 +1,7 @@
-use std::mem::size_of;
+use std::cell::RefCell;
+use std::path::Path;
 use std::ptr::copy_nonoverlapping as memcpy;
+use std::rc::Rc;
 use std::time::Instant;
 
 use crate::gfx::device::*;
@@ -7,78 +9,196 @@ use crate::gfx::pipeline::*;
 use crate::gfx::swapchain::*;
 use crate::gfx::*;
 use anyhow::{anyhow, Result};
-use cgmath::point3;
 use cgmath::vec3;
 use cgmath::Deg;
 use vulkanalia::loader::{LibloadingLoader, LIBRARY};
 use vulkanalia::vk;
-use vulkanalia::vk::ExtDebugUtilsExtension;
-use vulkanalia::vk::KhrSurfaceExtension;
 use vulkanalia::vk::KhrSwapchainExtension;
 use vulkanalia::window as vk_window;
 use winit::window::Window;
 
-use self::texture::create_texture_image;
+use self::camera::Camera;
+use self::features::DeviceFeatures;
+use self::memory::{Allocator, AllocatorStats};
+use self::model::*;
+use self::offscreen::*;
+use self::pipeline_cache::*;
+use self::resource::{SharedDevice, SharedInstance, Surface};
+use self::shader::ShaderWatcher;
+use self::texture::*;
+use self::validation::MessageCollector;
 use self::vertex::*;
 
 /// Vulkan app
-#[derive(Clone, Debug)]
+///
+/// Dropping the app waits for the device to go idle and then destroys every
+/// Vulkan object it owns, `data` first and the instance last.
+#[derive(Debug)]
 pub struct App {
-    pub entry: Entry,
-    pub instance: Instance,
     pub data: AppData,
-    pub device: Device,
+    pub device: SharedDevice,
+    pub instance: SharedInstance,
+    pub entry: Entry,
     pub frame: usize,
     pub resized: bool,
-    pub start: Instant,
+    pub camera: Camera,
+    /// When the last frame started, for measuring frame delta time.
+    pub last_frame: Instant,
+    /// The animation clock (in seconds), advanced by each frame's delta time.
+    pub elapsed: f32,
+    /// Overrides the animation clock (in seconds) for reproducible frames.
+    pub fixed_time: Option<f32>,
+    /// The objects submitted for the next frame.
+    draw_list: Vec<DrawItem>,
+    shader_watcher: Option<ShaderWatcher>,
 }
 
 impl App {
     /// Creates Vulkan app
-    pub unsafe fn create(window: &Window) -> Result<Self> {
+    pub unsafe fn create(window: &Window, options: AppOptions) -> Result<Self> {
+        Self::create_with(Some(window), vk::Extent2D::default(), options)
+    }
+
+    /// Creates Vulkan app that renders into an offscreen color image instead
+    /// of a window's swapchain. Finished frames are read back with
+    /// [`App::read_frame`].
+    pub unsafe fn create_headless(width: u32, height: u32, options: AppOptions) -> Result<Self> {
+        if width == 0 || height == 0 {
+            return Err(anyhow!("Invalid headless extent {}x{}.", width, height));
+        }
+
+        Self::create_with(None, vk::Extent2D { width, height }, options)
+    }
+
+    /// Lists every physical device with its score and whether it can render
+    /// headless frames.
+    pub unsafe fn list_devices(options: AppOptions) -> Result<Vec<DeviceCandidate>> {
+        Self::inspect_devices(None, options, |instance, data| {
+            get_device_candidates(instance, data)
+        })
+    }
+
+    /// Runs every suitability check on every physical device, including the
+    /// swapchain checks when given a window to present to.
+    pub unsafe fn diagnose(
+        window: Option<&Window>,
+        options: AppOptions,
+    ) -> Result<Vec<SuitabilityReport>> {
+        Self::inspect_devices(window, options, |instance, data| {
+            Ok(instance
+                .enumerate_physical_devices()?
+                .into_iter()
+                .map(|physical_device| SuitabilityReport::new(instance, data, physical_device))
+                .collect())
+        })
+    }
+
+    /// Creates just enough of the app (an instance, and a surface for
+    /// `window`) to inspect the physical devices with `f`.
+    unsafe fn inspect_devices<T>(
+        window: Option<&Window>,
+        options: AppOptions,
+        f: impl FnOnce(&Instance, &AppData) -> Result<T>,
+    ) -> Result<T> {
         let loader = LibloadingLoader::new(LIBRARY)?;
         let entry = Entry::new(loader).map_err(|b| anyhow!("{}", b))?;
-        let mut data = AppData::default();
+        let mut data = AppData {
+            headless: window.is_none(),
+            options,
+            ..Default::default()
+        };
+        let instance = create_instance(window, &entry, &mut data)?;
+        if let Some(window) = window {
+            let surface = vk_window::create_surface(&instance, &window, &window)?;
+            data.surface = Surface::new(&instance, surface);
+        }
+
+        f(&instance, &data)
+    }
+
+    unsafe fn create_with(
+        window: Option<&Window>,
+        extent: vk::Extent2D,
+        options: AppOptions,
+    ) -> Result<Self> {
+        let loader = LibloadingLoader::new(LIBRARY)?;
+        let entry = Entry::new(loader).map_err(|b| anyhow!("{}", b))?;
+        let mut data = AppData {
+            headless: window.is_none(),
+            options,
+            ..Default::default()
+        };
         let instance = create_instance(window, &entry, &mut data)?;
-        data.surface = vk_window::create_surface(&instance, &window, &window)?;
+        if let Some(window) = window {
+            let surface = vk_window::create_surface(&instance, &window, &window)?;
+            data.surface = Surface::new(&instance, surface);
+        }
         device::pick_physical_device(&instance, &mut data)?;
         let device = create_logical_device(&entry, &instance, &mut data)?;
-        create_swapchain(window, &instance, &device, &mut data)?;
+        data.allocator = Rc::new(RefCell::new(Allocator::new(
+            &instance,
+            data.physical_device,
+            &device,
+            features::device_api_version(&instance, &data, data.physical_device),
+        )));
+        match window {
+            Some(window) => create_swapchain(window, &instance, &device, &mut data)?,
+            None => create_offscreen_images(&instance, &device, &mut data, extent)?,
+        }
         create_swapchain_image_views(&device, &mut data)?;
         create_render_pass(&instance, &device, &mut data)?;
         create_descriptor_set_layout(&device, &mut data)?;
+        create_pipeline_cache(&instance, &device, &mut data)?;
         create_pipeline(&device, &mut data)?;
-        create_framebuffers(&device, &mut data)?;
         create_command_pool(&instance, &device, &mut data)?;
+        create_color_objects(&instance, &device, &mut data)?;
+        create_depth_objects(&instance, &device, &mut data)?;
+        create_framebuffers(&device, &mut data)?;
         create_texture_image(&instance, &device, &mut data)?;
-        create_vertex_buffer(&instance, &device, &mut data)?;
-        create_index_buffer(&instance, &device, &mut data)?;
+        create_texture_image_view(&device, &mut data)?;
+        create_texture_sampler(&device, &mut data)?;
+        create_default_mesh(&instance, &device, &mut data)?;
         create_uniform_buffers(&instance, &device, &mut data)?;
         create_descriptor_pool(&device, &mut data)?;
         create_descriptor_sets(&device, &mut data)?;
         create_command_buffers(&device, &mut data)?;
         create_sync_objects(&device, &mut data)?;
 
+        let shader_watcher = match &data.options.shader_dir {
+            Some(dir) => Some(ShaderWatcher::new(dir)?),
+            None => None,
+        };
+
+        data.messages.check_errors();
+
         Ok(Self {
-            entry,
-            instance,
             data,
             device,
+            instance,
+            entry,
             frame: 0,
             resized: false,
-            start: Instant::now(),
+            camera: Camera::default(),
+            last_frame: Instant::now(),
+            elapsed: 0.0,
+            fixed_time: None,
+            draw_list: Vec::new(),
+            shader_watcher,
         })
     }
 
     /// Renders a frame for our Vulkan app.
     pub unsafe fn render(&mut self, window: &Window) -> Result<()> {
+        self.tick();
+        self.reload_shaders()?;
+
         self.device
-            .wait_for_fences(&[self.data.in_flight_fences[self.frame]], true, u64::MAX)?;
+            .wait_for_fences(&[*self.data.in_flight_fences[self.frame]], true, u64::MAX)?;
 
         let result = self.device.acquire_next_image_khr(
-            self.data.swapchain,
+            *self.data.swapchain,
             u64::MAX,
-            self.data.image_available_semaphores[self.frame],
+            *self.data.image_available_semaphores[self.frame],
             vk::Fence::null(),
         );
 
@@ -88,192 +208,367 @@ impl App {
             Err(e) => return Err(anyhow!(e)),
         };
 
-        if !self.data.images_in_flight[image_index as usize].is_null() {
+        let wait_semaphores = &[*self.data.image_available_semaphores[self.frame]];
+        let signal_semaphores = &[*self.data.render_finished_semaphores[self.frame]];
+        self.submit_frame(image_index, wait_semaphores, signal_semaphores)?;
+
+        let swapchains = &[*self.data.swapchain];
+        let image_indices = &[image_index as u32];
+        let present_info = vk::PresentInfoKHR::builder()
+            .wait_semaphores(signal_semaphores)
+            .swapchains(swapchains)
+            .image_indices(image_indices);
+
+        let result = self
+            .device
+            .queue_present_khr(self.data.present_queue, &present_info);
+        let changed = result == Ok(vk::SuccessCode::SUBOPTIMAL_KHR)
+            || result == Err(vk::ErrorCode::OUT_OF_DATE_KHR);
+        if self.resized || changed {
+            self.resized = false;
+            self.recreate_swapchain(window)?;
+        } else if let Err(e) = result {
+            return Err(anyhow!(e));
+        }
+
+        self.frame = (self.frame + 1) % crate::MAX_FRAMES_IN_FLIGHT;
+
+        Ok(())
+    }
+
+    /// Renders a frame into the offscreen image of a headless app.
+    pub unsafe fn render_headless(&mut self) -> Result<()> {
+        self.tick();
+
+        self.device
+            .wait_for_fences(&[*self.data.in_flight_fences[self.frame]], true, u64::MAX)?;
+
+        self.submit_frame(0, &[], &[])?;
+
+        self.frame = (self.frame + 1) % crate::MAX_FRAMES_IN_FLIGHT;
+
+        Ok(())
+    }
+
+    /// Waits for the last headless frame to finish and reads it back to CPU
+    /// memory.
+    pub unsafe fn read_frame(&mut self) -> Result<Frame> {
+        if !self.data.headless {
+            return Err(anyhow!("Frame readback requires a headless app."));
+        }
+
+        self.device.device_wait_idle()?;
+        self.data.messages.check_errors();
+
+        read_offscreen_image(&self.instance, &self.device, &mut self.data, 0)
+    }
+
+    /// Updates this frame's camera, then records and submits its command
+    /// buffer drawing into the swapchain image at `image_index`.
+    unsafe fn submit_frame(
+        &mut self,
+        image_index: usize,
+        wait_semaphores: &[vk::Semaphore],
+        signal_semaphores: &[vk::Semaphore],
+    ) -> Result<()> {
+        if !self.data.images_in_flight[image_index].is_null() {
             self.device.wait_for_fences(
-                &[self.data.images_in_flight[image_index as usize]],
+                &[self.data.images_in_flight[image_index]],
                 true,
                 u64::MAX,
             )?;
         }
 
-        self.data.images_in_flight[image_index as usize] = self.data.in_flight_fences[self.frame];
+        self.data.images_in_flight[image_index] = *self.data.in_flight_fences[self.frame];
 
-        self.update_uniform_buffer(image_index)?;
+        self.update_uniform_buffer(self.frame)?;
 
-        let wait_semaphores = &[self.data.image_available_semaphores[self.frame]];
-        let wait_stages = &[vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT];
-        let command_buffers = &[self.data.command_buffers[image_index as usize]];
-        let signal_semaphores = &[self.data.render_finished_semaphores[self.frame]];
+        let wait_stages =
+            vec![vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT; wait_semaphores.len()];
+        let command_buffer = self.data.command_buffers[self.frame];
+        self.device.reset_command_pool(
+            *self.data.frame_command_pools[self.frame],
+            vk::CommandPoolResetFlags::empty(),
+        )?;
+        if self.draw_list.is_empty() {
+            self.draw_list = self.default_draw_list();
+        }
+        record_command_buffer(
+            &self.device,
+            &self.data,
+            command_buffer,
+            image_index,
+            self.frame,
+            &self.draw_list,
+        )?;
+        self.draw_list.clear();
+
+        let command_buffers = &[command_buffer];
         let submit_info = vk::SubmitInfo::builder()
             .wait_semaphores(wait_semaphores)
-            .wait_dst_stage_mask(wait_stages)
+            .wait_dst_stage_mask(&wait_stages)
             .command_buffers(command_buffers)
             .signal_semaphores(signal_semaphores);
 
         self.device
-            .reset_fences(&[self.data.in_flight_fences[self.frame]])?;
+            .reset_fences(&[*self.data.in_flight_fences[self.frame]])?;
 
         self.device.queue_submit(
             self.data.graphics_queue,
             &[submit_info],
-            self.data.in_flight_fences[self.frame],
+            *self.data.in_flight_fences[self.frame],
         )?;
 
-        let dependency = vk::SubpassDependency::builder()
-            .src_subpass(vk::SUBPASS_EXTERNAL)
-            .dst_subpass(0)
-            .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
-            .src_access_mask(vk::AccessFlags::empty())
-            .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
-            .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE);
+        self.data.messages.check_errors();
 
-        let swapchains = &[self.data.swapchain];
-        let image_indices = &[image_index as u32];
-        let present_info = vk::PresentInfoKHR::builder()
-            .wait_semaphores(signal_semaphores)
-            .swapchains(swapchains)
-            .image_indices(image_indices);
+        Ok(())
+    }
 
-        let result = self
-            .device
-            .queue_present_khr(self.data.present_queue, &present_info);
-        let changed = result == Ok(vk::SuccessCode::SUBOPTIMAL_KHR)
-            || result == Err(vk::ErrorCode::OUT_OF_DATE_KHR);
-        if self.resized || changed {
-            self.resized = false;
-            self.recreate_swapchain(window)?;
-        } else if let Err(e) = result {
-            return Err(anyhow!(e));
+    /// Replaces the texture sampler, rewriting the descriptor sets that
+    /// reference it.
+    pub unsafe fn set_sampler_options(&mut self, options: SamplerOptions) -> Result<()> {
+        self.device.device_wait_idle()?;
+
+        self.data.sampler_options = options;
+        create_texture_sampler(&self.device, &mut self.data)?;
+
+        create_descriptor_pool(&self.device, &mut self.data)?;
+        create_descriptor_sets(&self.device, &mut self.data)?;
+
+        Ok(())
+    }
+
+    /// Replaces the drawn meshes with the shapes in an `.obj` file.
+    pub unsafe fn load_model(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        let (meshes, materials) =
+            load_model(&self.instance, &self.device, &mut self.data, path.as_ref())?;
+
+        // Frames in flight may still draw the old meshes.
+        self.device.device_wait_idle()?;
+
+        self.data.meshes = meshes;
+        self.data.materials = materials;
+        self.draw_list.clear();
+
+        Ok(())
+    }
+
+    /// Adds an object to the next frame.
+    ///
+    /// The draw list is cleared after every frame. When nothing was submitted,
+    /// every mesh is drawn spinning about the origin.
+    pub fn submit(&mut self, item: DrawItem) -> Result<()> {
+        if item.mesh >= self.data.meshes.len() {
+            return Err(anyhow!("No mesh with index {}.", item.mesh));
         }
 
-        self.frame = (self.frame + 1) % crate::MAX_FRAMES_IN_FLIGHT;
+        if let Some(material) = item.material.filter(|m| *m >= self.data.materials.len()) {
+            return Err(anyhow!("No material with index {}.", material));
+        }
 
+        self.draw_list.push(item);
         Ok(())
     }
 
-    /// Destroys our Vulkan app.
-    pub unsafe fn destroy(&mut self) {
-        self.device.device_wait_idle().unwrap();
+    /// The device features enabled for this app.
+    pub fn features(&self) -> &DeviceFeatures {
+        &self.data.features
+    }
 
-        self.destroy_swapchain();
-        self.device.destroy_image(self.data.texture_image, None);
-        self.device
-            .free_memory(self.data.texture_image_memory, None);
-        self.device
-            .destroy_descriptor_set_layout(self.data.descriptor_set_layout, None);
-        self.device.destroy_buffer(self.data.index_buffer, None);
-        self.device.free_memory(self.data.index_buffer_memory, None);
-        self.device.destroy_buffer(self.data.vertex_buffer, None);
-        self.device
-            .free_memory(self.data.vertex_buffer_memory, None);
+    pub fn meshes(&self) -> &[Mesh] {
+        &self.data.meshes
+    }
 
-        self.data
-            .in_flight_fences
-            .iter()
-            .for_each(|f| self.device.destroy_fence(*f, None));
-        self.data
-            .render_finished_semaphores
-            .iter()
-            .for_each(|s| self.device.destroy_semaphore(*s, None));
-        self.data
-            .image_available_semaphores
-            .iter()
-            .for_each(|s| self.device.destroy_semaphore(*s, None));
-        self.device
-            .destroy_command_pool(self.data.command_pool, None);
-        self.device
-            .destroy_command_pool(self.data.transfer_command_pool, None);
-        self.device.destroy_device(None);
-        self.instance.destroy_surface_khr(self.data.surface, None);
+    pub fn materials(&self) -> &[Material] {
+        &self.data.materials
+    }
+
+    /// The messages reported by the validation layer, which stay empty while
+    /// validation is disabled.
+    pub fn validation_messages(&self) -> &MessageCollector {
+        &self.data.messages
+    }
+
+    /// The present mode of the swapchain.
+    pub fn present_mode(&self) -> vk::PresentModeKHR {
+        self.data.present_mode
+    }
+
+    /// Sets the present modes to try in order, recreating the swapchain with
+    /// the first one the surface supports.
+    pub unsafe fn set_present_modes(
+        &mut self,
+        window: &Window,
+        modes: &[PresentMode],
+    ) -> Result<()> {
+        self.data.options.present_modes = modes.to_vec();
+        if self.data.headless {
+            return Ok(());
+        }
+
+        self.recreate_swapchain(window)
+    }
 
-        if crate::VALIDATION_ENABLED {
-            self.instance
-                .destroy_debug_utils_messenger_ext(self.data.messenger, None);
+    /// Switches to the next present mode the surface supports.
+    ///
+    /// The mode is moved to the front of the configured preferences, so the
+    /// rest of them are still fallbacks if the surface changes.
+    pub unsafe fn cycle_present_mode(&mut self, window: &Window) -> Result<()> {
+        if self.data.headless {
+            return Err(anyhow!("Headless apps have no present mode."));
         }
 
-        self.instance.destroy_instance(None);
+        let support = SwapchainSupport::get(&self.instance, &self.data, self.data.physical_device)?;
+        let modes = PresentMode::ALL;
+        let current = modes
+            .iter()
+            .position(|m| m.to_vk() == self.data.present_mode)
+            .unwrap_or(0);
+        let next = (1..=modes.len())
+            .map(|i| modes[(current + i) % modes.len()])
+            .find(|m| support.present_modes.contains(&m.to_vk()))
+            .unwrap_or(PresentMode::Vsync);
+
+        let preferences = match self.data.options.present_modes.as_slice() {
+            [] => PresentMode::DEFAULT_PREFERENCES,
+            modes => modes,
+        };
+        let modes = std::iter::once(next)
+            .chain(preferences.iter().cloned().filter(|m| *m != next))
+            .collect::<Vec<_>>();
+
+        self.set_present_modes(window, &modes)
+    }
+
+    /// Reports GPU memory usage of the app's allocator.
+    pub fn memory_stats(&self) -> AllocatorStats {
+        self.data.allocator.borrow().stats()
     }
 
     unsafe fn recreate_swapchain(&mut self, window: &Window) -> Result<()> {
         self.device.device_wait_idle()?;
-        self.destroy_swapchain();
+        self.release_swapchain();
         create_swapchain(window, &self.instance, &self.device, &mut self.data)?;
         create_swapchain_image_views(&self.device, &mut self.data)?;
         create_render_pass(&self.instance, &self.device, &mut self.data)?;
         create_pipeline(&self.device, &mut self.data)?;
+        create_color_objects(&self.instance, &self.device, &mut self.data)?;
+        create_depth_objects(&self.instance, &self.device, &mut self.data)?;
         create_framebuffers(&self.device, &mut self.data)?;
-        create_uniform_buffers(&self.instance, &self.device, &mut self.data)?;
-        create_descriptor_pool(&self.device, &mut self.data)?;
-        create_descriptor_sets(&self.device, &mut self.data)?;
-        create_command_buffers(&self.device, &mut self.data)?;
         self.data
             .images_in_flight
             .resize(self.data.swapchain_images.len(), vk::Fence::null());
         Ok(())
     }
 
-    /// Destroys the parts of our Vulkan app related to the swapchain.
-    unsafe fn destroy_swapchain(&mut self) {
-        self.device
-            .destroy_descriptor_pool(self.data.descriptor_pool, None);
-        self.data
-            .uniform_buffers
-            .iter()
-            .for_each(|b| self.device.destroy_buffer(*b, None));
-        self.data
-            .uniform_buffers_memory
-            .iter()
-            .for_each(|m| self.device.free_memory(*m, None));
-        self.device
-            .free_command_buffers(self.data.command_pool, &self.data.command_buffers);
-        self.data
-            .framebuffers
-            .iter()
-            .for_each(|f| self.device.destroy_framebuffer(*f, None));
-        self.device.destroy_pipeline(self.data.pipeline, None);
-        self.device
-            .destroy_pipeline_layout(self.data.pipeline_layout, None);
-        self.device.destroy_render_pass(self.data.render_pass, None);
-        self.data
-            .swapchain_image_views
-            .iter()
-            .for_each(|v| self.device.destroy_image_view(*v, None));
-        self.device.destroy_swapchain_khr(self.data.swapchain, None);
+    /// Releases the parts of our Vulkan app related to the swapchain, except
+    /// the swapchain itself, which is handed to its replacement.
+    unsafe fn release_swapchain(&mut self) {
+        self.data.framebuffers.clear();
+        self.data.pipeline = Default::default();
+        self.data.pipeline_layout = Default::default();
+        self.data.render_pass = Default::default();
+        self.data.color_image_view = Default::default();
+        self.data.color_image = Default::default();
+        self.data.depth_image_view = Default::default();
+        self.data.depth_image = Default::default();
+        self.data.swapchain_image_views.clear();
     }
 
-    unsafe fn update_uniform_buffer(&self, image_index: usize) -> Result<()> {
-        let time = self.start.elapsed().as_secs_f32();
+    /// Advances the animation clock and the camera by the time since the
+    /// last frame.
+    fn tick(&mut self) {
+        let now = Instant::now();
+        let dt = (now - self.last_frame).as_secs_f32();
+        self.last_frame = now;
 
-        let model = Mat4::from_axis_angle(vec3(0.0, 0.0, 1.0), Deg(90.0) * time);
-        let view = Mat4::look_at_rh(
-            point3(2.0, 2.0, 2.0),
-            point3(0.0, 0.0, 0.0),
-            vec3(0.0, 0.0, 1.0),
-        );
+        self.elapsed += dt;
+        self.camera.update(dt);
+    }
 
-        let mut proj = cgmath::perspective(
-            Deg(45.0),
+    /// Rebuilds the graphics pipeline if any of its shaders were recompiled
+    /// since the last frame.
+    ///
+    /// Called between frames, so the old pipeline is only destroyed once the
+    /// device is idle. If the new pipeline can't be created the old one (and
+    /// its shaders) stays in use.
+    unsafe fn reload_shaders(&mut self) -> Result<()> {
+        let Some(watcher) = &self.shader_watcher else {
+            return Ok(());
+        };
+
+        let mut previous = Vec::new();
+        for (name, bytecode) in watcher.recompile() {
+            if [VERT_SHADER, FRAG_SHADER].contains(&name.as_str()) {
+                previous.push((name.clone(), self.data.shaders.insert(&name, bytecode)));
+            }
+        }
+
+        if previous.is_empty() {
+            return Ok(());
+        }
+
+        match create_graphics_pipeline(&self.device, &self.data) {
+            Ok(pipeline) => {
+                self.device.device_wait_idle()?;
+                self.data.pipeline = pipeline;
+                info!("Rebuilt the graphics pipeline.");
+            }
+            Err(e) => {
+                error!("Failed to rebuild the graphics pipeline: {}", e);
+                for (name, bytecode) in previous.into_iter().rev() {
+                    if let Some(bytecode) = bytecode {
+                        self.data.shaders.insert(&name, bytecode);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The animation clock, or the fixed animation time.
+    fn time(&self) -> f32 {
+        self.fixed_time.unwrap_or(self.elapsed)
+    }
+
+    /// Every mesh spinning about the origin.
+    fn default_draw_list(&self) -> Vec<DrawItem> {
+        let transform = Mat4::from_axis_angle(vec3(0.0, 0.0, 1.0), Deg(90.0) * self.time());
+        (0..self.data.meshes.len())
+            .map(|mesh| DrawItem {
+                mesh,
+                material: None,
+                transform,
+                label: None,
+            })
+            .collect()
+    }
+
+    unsafe fn update_uniform_buffer(&self, frame: usize) -> Result<()> {
+        let view = self.camera.view();
+        let proj = self.camera.proj(
             self.data.swapchain_extent.width as f32 / self.data.swapchain_extent.height as f32,
-            0.1,
-            10.0,
         );
 
-        proj[1][1] *= -1.0;
-
-        let ubo = UniformBufferObject { model, view, proj };
+        let ubo = CameraUniform { view, proj };
 
-        let memory = self.device.map_memory(
-            self.data.uniform_buffers_memory[image_index],
-            0,
-            size_of::<UniformBufferObject>() as u64,
-            vk::MemoryMapFlags::empty(),
-        )?;
+        let memory = self.data.uniform_buffers[frame].mapped_ptr()?;
 
         memcpy(&ubo, memory.cast(), 1);
 
-        self.device
-            .unmap_memory(self.data.uniform_buffers_memory[image_index]);
-
         Ok(())
     }
 }
+
+impl Drop for App {
+    fn drop(&mut self) {
+        if let Err(e) = unsafe { self.device.device_wait_idle() } {
+            error!("Failed to wait for the device before destroying the app: {}", e);
+        }
+
+        if let Err(e) = unsafe { save_pipeline_cache(&self.device, &self.data) } {
+            warn!("Failed to save the pipeline cache: {}", e);
+        }
+    }
+}
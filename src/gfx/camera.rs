@@ -0,0 +1,241 @@
+use crate::gfx::vertex::{Mat4, Vec3};
+use cgmath::{point3, vec3, Deg, InnerSpace, Point3, Rad};
+use log::*;
+use std::collections::HashSet;
+use winit::event::{DeviceEvent, ElementState, MouseButton, MouseScrollDelta, WindowEvent};
+use winit::keyboard::{KeyCode, PhysicalKey};
+use winit::window::{CursorGrabMode, Window};
+
+const UP: Vec3 = vec3(0.0, 0.0, 1.0);
+const MAX_PITCH: Rad<f32> = Rad(1.55);
+
+/// How input moves the camera.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CameraMode {
+    /// WASD moves the eye and the mouse turns it while the cursor is grabbed.
+    Fly,
+    /// Dragging with the left button circles the target and scrolling zooms.
+    Orbit,
+}
+
+/// A perspective camera driven by window and device input.
+///
+/// Both modes share one representation: the eye sits `distance` behind
+/// `target`, looking along the direction given by `yaw` and `pitch` (with +Z
+/// up). Flying moves the target along with the eye; orbiting keeps it fixed.
+#[derive(Clone, Debug)]
+pub struct Camera {
+    pub mode: CameraMode,
+    pub target: Point3<f32>,
+    pub distance: f32,
+    pub yaw: Rad<f32>,
+    pub pitch: Rad<f32>,
+    pub fov: Deg<f32>,
+    pub near: f32,
+    pub far: f32,
+    /// Fly speed in units per second.
+    pub speed: f32,
+    /// Rotation per pixel of mouse motion.
+    pub sensitivity: Rad<f32>,
+    pressed: HashSet<KeyCode>,
+    dragging: bool,
+    grabbed: bool,
+    look: (f32, f32),
+    zoom: f32,
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Self::look_at(point3(2.0, 2.0, 2.0), point3(0.0, 0.0, 0.0))
+    }
+}
+
+impl Camera {
+    /// Creates an orbit camera at `eye` looking at `target`.
+    pub fn look_at(eye: Point3<f32>, target: Point3<f32>) -> Self {
+        let offset = target - eye;
+        let forward = offset.normalize();
+
+        Self {
+            mode: CameraMode::Orbit,
+            target,
+            distance: offset.magnitude(),
+            yaw: Rad(forward.y.atan2(forward.x)),
+            pitch: Rad(forward.z.asin()),
+            fov: Deg(45.0),
+            near: 0.1,
+            far: 10.0,
+            speed: 2.0,
+            sensitivity: Rad(0.003),
+            pressed: HashSet::new(),
+            dragging: false,
+            grabbed: false,
+            look: (0.0, 0.0),
+            zoom: 0.0,
+        }
+    }
+
+    /// The direction the camera is looking in.
+    pub fn forward(&self) -> Vec3 {
+        let (sin_yaw, cos_yaw) = self.yaw.0.sin_cos();
+        let (sin_pitch, cos_pitch) = self.pitch.0.sin_cos();
+        vec3(cos_pitch * cos_yaw, cos_pitch * sin_yaw, sin_pitch)
+    }
+
+    pub fn eye(&self) -> Point3<f32> {
+        self.target - self.forward() * self.distance
+    }
+
+    pub fn view(&self) -> Mat4 {
+        Mat4::look_to_rh(self.eye(), self.forward(), UP)
+    }
+
+    /// The projection for a viewport with the given aspect ratio, flipped for
+    /// Vulkan's downward Y axis.
+    pub fn proj(&self, aspect: f32) -> Mat4 {
+        let mut proj = cgmath::perspective(self.fov, aspect, self.near, self.far);
+        proj[1][1] *= -1.0;
+        proj
+    }
+
+    /// Whether mouse motion should be captured, which the window applies with
+    /// [`set_cursor_grab`].
+    pub fn cursor_grabbed(&self) -> bool {
+        self.grabbed
+    }
+
+    /// Records keyboard, button and scroll input.
+    ///
+    /// Tab switches modes, a left click in fly mode grabs the cursor and
+    /// Escape releases it.
+    pub fn handle_window_event(&mut self, event: &WindowEvent) {
+        match event {
+            WindowEvent::KeyboardInput { event, .. } => {
+                let PhysicalKey::Code(code) = event.physical_key else {
+                    return;
+                };
+
+                if event.state == ElementState::Released {
+                    self.pressed.remove(&code);
+                    return;
+                }
+
+                if event.repeat {
+                    return;
+                }
+
+                match code {
+                    KeyCode::Tab => self.toggle_mode(),
+                    KeyCode::Escape => self.grabbed = false,
+                    _ => {
+                        self.pressed.insert(code);
+                    }
+                }
+            }
+            WindowEvent::MouseInput {
+                state,
+                button: MouseButton::Left,
+                ..
+            } => {
+                self.dragging = *state == ElementState::Pressed;
+                if self.dragging && self.mode == CameraMode::Fly {
+                    self.grabbed = true;
+                }
+            }
+            WindowEvent::MouseWheel { delta, .. } => {
+                self.zoom += match delta {
+                    MouseScrollDelta::LineDelta(_, y) => *y,
+                    MouseScrollDelta::PixelDelta(position) => position.y as f32 / 40.0,
+                };
+            }
+            WindowEvent::Focused(false) => {
+                self.pressed.clear();
+                self.dragging = false;
+                self.grabbed = false;
+            }
+            _ => {}
+        }
+    }
+
+    /// Records raw mouse motion, which keeps arriving while the cursor is
+    /// grabbed.
+    pub fn handle_device_event(&mut self, event: &DeviceEvent) {
+        if let DeviceEvent::MouseMotion { delta } = event {
+            let looking = match self.mode {
+                CameraMode::Fly => self.grabbed,
+                CameraMode::Orbit => self.dragging,
+            };
+
+            if looking {
+                self.look.0 += delta.0 as f32;
+                self.look.1 += delta.1 as f32;
+            }
+        }
+    }
+
+    /// Applies the input recorded since the last update over `dt` seconds.
+    pub fn update(&mut self, dt: f32) {
+        let (dx, dy) = std::mem::take(&mut self.look);
+        let zoom = std::mem::take(&mut self.zoom);
+        let eye = self.eye();
+
+        self.yaw -= self.sensitivity * dx;
+        self.pitch -= self.sensitivity * dy;
+        self.pitch.0 = self.pitch.0.clamp(-MAX_PITCH.0, MAX_PITCH.0);
+
+        match self.mode {
+            CameraMode::Fly => {
+                let forward = self.forward();
+                let right = forward.cross(UP).normalize();
+                let mut direction = vec3(0.0, 0.0, 0.0);
+                for (key, axis) in [
+                    (KeyCode::KeyW, forward),
+                    (KeyCode::KeyS, -forward),
+                    (KeyCode::KeyD, right),
+                    (KeyCode::KeyA, -right),
+                    (KeyCode::Space, UP),
+                    (KeyCode::ShiftLeft, -UP),
+                ] {
+                    if self.pressed.contains(&key) {
+                        direction += axis;
+                    }
+                }
+
+                // Turn about the eye rather than the target.
+                self.target = eye + forward * self.distance;
+                if direction.magnitude2() > 0.0 {
+                    self.target += direction.normalize() * self.speed * dt;
+                }
+            }
+            CameraMode::Orbit => {
+                let max = self.far - self.near;
+                self.distance = (self.distance * 0.9f32.powf(zoom)).clamp(self.near, max);
+            }
+        }
+    }
+
+    fn toggle_mode(&mut self) {
+        self.mode = match self.mode {
+            CameraMode::Fly => CameraMode::Orbit,
+            CameraMode::Orbit => CameraMode::Fly,
+        };
+        self.grabbed = false;
+        info!("Camera mode: {:?}", self.mode);
+    }
+}
+
+/// Confines (or releases) the cursor and hides it while grabbed.
+pub fn set_cursor_grab(window: &Window, grabbed: bool) {
+    let result = if grabbed {
+        window
+            .set_cursor_grab(CursorGrabMode::Locked)
+            .or_else(|_| window.set_cursor_grab(CursorGrabMode::Confined))
+    } else {
+        window.set_cursor_grab(CursorGrabMode::None)
+    };
+
+    if let Err(e) = result {
+        warn!("Failed to set cursor grab: {}", e);
+    }
+    window.set_cursor_visible(!grabbed);
+}
@@ -0,0 +1,97 @@
+//! Object names and command buffer labels from `VK_EXT_debug_utils`, which
+//! show up in validation messages and graphics debuggers.
+//!
+//! These do nothing unless the extension is enabled. Without the
+//! `debug-utils` feature the check is a `cfg!` constant, so calls are still
+//! type-checked but optimized away.
+
+use crate::gfx::resource::OwnedDevice;
+use log::*;
+use std::ffi::CString;
+use vulkanalia::prelude::v1_0::*;
+use vulkanalia::vk::ExtDebugUtilsExtension;
+
+/// Label colors for the kinds of work recorded into command buffers.
+pub const RENDER_PASS_COLOR: [f32; 4] = [0.2, 0.6, 1.0, 1.0];
+pub const TRANSFER_COLOR: [f32; 4] = [1.0, 0.6, 0.2, 1.0];
+pub const DRAW_COLOR: [f32; 4] = [0.4, 0.8, 0.4, 1.0];
+
+fn enabled(device: &OwnedDevice) -> bool {
+    cfg!(feature = "debug-utils") && device.instance().debug_utils()
+}
+
+/// Gives an object created from `device` a human-readable name.
+pub unsafe fn set_object_name<T>(device: &OwnedDevice, handle: T, name: &str)
+where
+    T: vk::Handle,
+    T::Repr: TryInto<u64>,
+{
+    if !enabled(device) || handle.is_null() {
+        return;
+    }
+
+    // Dispatchable handles are pointers, which always fit.
+    let raw = handle.as_raw().try_into().unwrap_or_default();
+    let name = c_string(name);
+    let info = vk::DebugUtilsObjectNameInfoEXT::builder()
+        .object_type(T::TYPE)
+        .object_handle(raw)
+        .object_name(name.as_bytes_with_nul());
+
+    if let Err(e) = device
+        .instance()
+        .set_debug_utils_object_name_ext(device.handle(), &info)
+    {
+        warn!("Failed to name {:?} `{}`: {}", T::TYPE, name.to_string_lossy(), e);
+    }
+}
+
+/// A region of a command buffer shown as a group in graphics debuggers,
+/// ended when dropped.
+#[must_use = "the label ends as soon as it is dropped"]
+pub struct Label<'a> {
+    device: &'a OwnedDevice,
+    command_buffer: vk::CommandBuffer,
+}
+
+impl Drop for Label<'_> {
+    fn drop(&mut self) {
+        if enabled(self.device) {
+            unsafe {
+                self.device
+                    .instance()
+                    .cmd_end_debug_utils_label_ext(self.command_buffer)
+            };
+        }
+    }
+}
+
+/// Begins a labeled region of `command_buffer`, which lasts until the
+/// returned [`Label`] is dropped.
+pub unsafe fn begin_label<'a>(
+    device: &'a OwnedDevice,
+    command_buffer: vk::CommandBuffer,
+    name: &str,
+    color: [f32; 4],
+) -> Label<'a> {
+    if enabled(device) {
+        let name = c_string(name);
+        let info = vk::DebugUtilsLabelEXT::builder()
+            .label_name(name.as_bytes_with_nul())
+            .color(color);
+        device
+            .instance()
+            .cmd_begin_debug_utils_label_ext(command_buffer, &info);
+    }
+
+    Label {
+        device,
+        command_buffer,
+    }
+}
+
+/// Names can't contain nul bytes, so anything after one is dropped.
+fn c_string(name: &str) -> CString {
+    let name = name.split('\0').next().unwrap_or_default();
+    CString::new(name).unwrap_or_default()
+}
@@ -4,72 +4,451 @@
     clippy::too_many_arguments,
     clippy::unnecessary_wraps
 )]
+use crate::gfx::debug::set_object_name;
+use crate::gfx::features::{device_api_version, get_supported_features};
+use crate::gfx::resource::{OwnedDevice, SharedDevice, SharedInstance};
 use crate::gfx::*;
 use crate::swapchain::*;
 use anyhow::{anyhow, Result};
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
 use thiserror::Error;
-use vulkanalia::vk::KhrSurfaceExtension;
+use vulkanalia::vk::{InstanceV1_1, KhrSurfaceExtension};
+use vulkanalia::Version;
 
 #[derive(Debug, Error)]
-#[error("Missing {0}.")]
-pub struct SuitabilityError(pub &'static str);
+#[error("{0}")]
+pub struct SuitabilityError(pub String);
 
+/// No physical device passed every suitability check.
+#[derive(Debug, Error)]
+#[error("Failed to find suitable physical device.\n{}", format_reports(.0))]
+pub struct NoSuitableDeviceError(pub Vec<SuitabilityReport>);
+
+fn format_reports(reports: &[SuitabilityReport]) -> String {
+    reports.iter().map(|r| r.to_string()).collect()
+}
+
+/// Picks a physical device by [`DeviceSelector`], or else the suitable device
+/// with the highest score.
 pub unsafe fn pick_physical_device(instance: &Instance, data: &mut AppData) -> Result<()> {
-    for physical_device in instance.enumerate_physical_devices()? {
-        let properties = instance.get_physical_device_properties(physical_device);
+    let candidates = get_device_candidates(instance, data)?;
+    for candidate in &candidates {
+        match &candidate.unsuitable {
+            Some(reason) => warn!("Skipping physical device (`{}`): {}", candidate.name, reason),
+            None => debug!("Physical device `{}` scored {}.", candidate.name, candidate.score),
+        }
+    }
 
-        if let Err(error) = check_physical_device(instance, data, physical_device) {
-            warn!("Skipping physical device (`{}`).", properties.device_name)
-        } else {
-            info!("Selected physical device (`{}`).", properties.device_name);
-            data.physical_device = physical_device;
-            return Ok(());
+    let candidate = match &data.options.device {
+        Some(selector) => {
+            let candidate = candidates
+                .iter()
+                .find(|c| selector.matches(c))
+                .ok_or_else(|| anyhow!("No physical device matches `{}`.", selector))?;
+            if !candidate.report.is_suitable() {
+                return Err(anyhow!(
+                    "Selected physical device `{}` is not suitable.\n{}",
+                    candidate.name,
+                    candidate.report
+                ));
+            }
+            candidate
+        }
+        None => candidates
+            .iter()
+            .filter(|c| c.unsuitable.is_none())
+            .max_by_key(|c| c.score)
+            .ok_or_else(|| {
+                NoSuitableDeviceError(candidates.iter().map(|c| c.report.clone()).collect())
+            })?,
+    };
+
+    info!("Selected physical device (`{}`).", candidate.name);
+    data.physical_device = candidate.physical_device;
+    data.msaa_samples = get_msaa_samples(instance, data);
+
+    Ok(())
+}
+
+/// Chooses a physical device by its index in enumeration order, its UUID or a
+/// case-insensitive substring of its name.
+///
+/// An index is written `#1` so that names made of digits, like `3080`, are
+/// matched against device names instead.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeviceSelector {
+    Index(usize),
+    Uuid([u8; vk::UUID_SIZE]),
+    Name(String),
+}
+
+impl DeviceSelector {
+    pub fn matches(&self, candidate: &DeviceCandidate) -> bool {
+        match self {
+            Self::Index(index) => candidate.index == *index,
+            Self::Uuid(uuid) => candidate.uuid == Some(*uuid),
+            Self::Name(name) => candidate
+                .name
+                .to_lowercase()
+                .contains(&name.to_lowercase()),
+        }
+    }
+}
+
+impl FromStr for DeviceSelector {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.is_empty() {
+            return Err(anyhow!("Empty physical device selector."));
         }
+
+        if let Some(index) = s.strip_prefix('#') {
+            let index = index
+                .parse()
+                .map_err(|_| anyhow!("Invalid physical device index `{}`.", s))?;
+            return Ok(Self::Index(index));
+        }
+
+        let hex = s.replace('-', "");
+        if hex.len() == 2 * vk::UUID_SIZE && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            let mut uuid = [0; vk::UUID_SIZE];
+            for (i, byte) in uuid.iter_mut().enumerate() {
+                *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)?;
+            }
+            return Ok(Self::Uuid(uuid));
+        }
+
+        Ok(Self::Name(s.into()))
     }
+}
 
-    Err(anyhow!("Failed to find suitable physical device."))
+impl fmt::Display for DeviceSelector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Index(index) => write!(f, "#{}", index),
+            Self::Uuid(uuid) => write!(f, "{}", format_uuid(uuid)),
+            Self::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// A physical device and how well it suits the app.
+#[derive(Clone, Debug)]
+pub struct DeviceCandidate {
+    /// The position of the device in enumeration order.
+    pub index: usize,
+    pub physical_device: vk::PhysicalDevice,
+    pub name: String,
+    pub device_type: vk::PhysicalDeviceType,
+    /// Only known for devices supporting Vulkan 1.1.
+    pub uuid: Option<[u8; vk::UUID_SIZE]>,
+    /// The size of all device-local memory heaps, in bytes.
+    pub memory: u64,
+    pub score: u64,
+    /// Why the device can't be used, if it can't.
+    pub unsuitable: Option<String>,
+    pub report: SuitabilityReport,
+}
+
+impl fmt::Display for DeviceCandidate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "#{} {} ({:?}, {} MiB",
+            self.index,
+            self.name,
+            self.device_type,
+            self.memory / (1024 * 1024)
+        )?;
+        if let Some(uuid) = &self.uuid {
+            write!(f, ", {}", format_uuid(uuid))?;
+        }
+        write!(f, ") score {}", self.score)?;
+        if let Some(reason) = &self.unsuitable {
+            write!(f, ", unsuitable: {}", reason)?;
+        }
+        Ok(())
+    }
 }
 
-pub unsafe fn check_physical_device(
+/// Lists every physical device with its score and suitability.
+pub unsafe fn get_device_candidates(
+    instance: &Instance,
+    data: &AppData,
+) -> Result<Vec<DeviceCandidate>> {
+    instance
+        .enumerate_physical_devices()?
+        .into_iter()
+        .enumerate()
+        .map(|(index, physical_device)| {
+            let properties = instance.get_physical_device_properties(physical_device);
+            let report = SuitabilityReport::new(instance, data, physical_device);
+            Ok(DeviceCandidate {
+                index,
+                physical_device,
+                name: properties.device_name.to_string(),
+                device_type: properties.device_type,
+                uuid: get_device_uuid(instance, data, physical_device),
+                memory: get_device_local_memory(instance, physical_device),
+                score: score_physical_device(instance, data, physical_device),
+                unsuitable: report.check().err().map(|e| e.to_string()),
+                report,
+            })
+        })
+        .collect()
+}
+
+/// Scores a device by type (discrete > integrated > virtual > CPU), then by
+/// device-local memory in MiB, with each supported optional feature worth as
+/// much as 1 GiB of memory.
+pub unsafe fn score_physical_device(
     instance: &Instance,
     data: &AppData,
     physical_device: vk::PhysicalDevice,
-) -> Result<()> {
-    // info!("{:?}", physical_device);
-    QueueFamilyIndices::get(instance, data, physical_device)?;
-    check_physical_device_extensions(instance, physical_device)?;
-    println!(
-        "Checking device: {}",
-        instance
-            .get_physical_device_properties(physical_device)
-            .device_name
-    );
+) -> u64 {
+    let properties = instance.get_physical_device_properties(physical_device);
+    let type_rank = match properties.device_type {
+        vk::PhysicalDeviceType::DISCRETE_GPU => 4,
+        vk::PhysicalDeviceType::INTEGRATED_GPU => 3,
+        vk::PhysicalDeviceType::VIRTUAL_GPU => 2,
+        vk::PhysicalDeviceType::CPU => 1,
+        _ => 0,
+    };
+
+    let memory = get_device_local_memory(instance, physical_device);
+    let memory_mib = (memory / (1024 * 1024)).min(999_999);
 
+    let supported = get_supported_features(instance, data, physical_device);
+    let optional_features = data
+        .options
+        .features
+        .optional
+        .intersection(&supported)
+        .names()
+        .len() as u64;
+
+    type_rank * 10_000_000 + memory_mib + optional_features * 1024
+}
+
+/// The size of all device-local memory heaps, in bytes.
+unsafe fn get_device_local_memory(instance: &Instance, physical_device: vk::PhysicalDevice) -> u64 {
+    let memory = instance.get_physical_device_memory_properties(physical_device);
+    memory.memory_heaps[..memory.memory_heap_count as usize]
+        .iter()
+        .filter(|h| h.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL))
+        .map(|h| h.size)
+        .sum()
+}
+
+/// Returns the device UUID, which needs Vulkan 1.1 from both the instance and
+/// the device.
+unsafe fn get_device_uuid(
+    instance: &Instance,
+    data: &AppData,
+    physical_device: vk::PhysicalDevice,
+) -> Option<[u8; vk::UUID_SIZE]> {
     let properties = instance.get_physical_device_properties(physical_device);
-    // if properties.device_type != vk::PhysicalDeviceType::DISCRETE_GPU {
-    //     return Err(anyhow!(SuitabilityError(
-    //         "Only Discrete GPUs are supported."
-    //     )));
-    // }
+    let device_version = Version::from(properties.api_version);
+    if data.api_version < Version::V1_1_0 || device_version < Version::V1_1_0 {
+        return None;
+    }
+
+    let mut id_properties = vk::PhysicalDeviceIDProperties::default();
+    let mut properties = vk::PhysicalDeviceProperties2::builder().push_next(&mut id_properties);
+    instance.get_physical_device_properties2(physical_device, &mut properties);
+
+    Some(id_properties.device_uuid.0)
+}
+
+fn format_uuid(uuid: &[u8; vk::UUID_SIZE]) -> String {
+    let hex = uuid.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// The outcome of every suitability check for a physical device.
+#[derive(Clone, Debug)]
+pub struct SuitabilityReport {
+    pub name: String,
+    /// Each check's name and either what was found or why it failed.
+    pub checks: Vec<(&'static str, Result<String, String>)>,
+}
+
+impl SuitabilityReport {
+    /// Runs every check, rather than stopping at the first failure.
+    pub unsafe fn new(
+        instance: &Instance,
+        data: &AppData,
+        physical_device: vk::PhysicalDevice,
+    ) -> Self {
+        let properties = instance.get_physical_device_properties(physical_device);
+        let mut checks = Vec::new();
+
+        let queue_families = QueueFamilyIndices::get(instance, data, physical_device)
+            .map(|i| {
+                format!(
+                    "graphics {}, present {}, transfer {} (queue {})",
+                    i.graphics, i.present, i.transfer, i.transfer_queue
+                )
+            })
+            .map_err(|e| e.to_string());
+        checks.push(("queue families", queue_families));
+
+        let extensions = check_physical_device_extensions(instance, data, physical_device)
+            .map(|_| format!("{} required", required_device_extensions(data).len()))
+            .map_err(|e| e.to_string());
+        checks.push(("extensions", extensions));
+
+        let supported = get_supported_features(instance, data, physical_device);
+        let missing = data.options.features.required.missing_from(&supported);
+        let features = if missing.is_empty() {
+            let optional = data.options.features.optional;
+            Ok(format!(
+                "{} required, {} of {} optional",
+                data.options.features.required.names().len(),
+                optional.intersection(&supported).names().len(),
+                optional.names().len()
+            ))
+        } else {
+            Err(format!("missing {}", missing.join(", ")))
+        };
+        checks.push(("features", features));
+
+        if data.headless {
+            checks.push(("swapchain", Ok("not needed when headless".into())));
+        } else {
+            match SwapchainSupport::get(instance, data, physical_device) {
+                Ok(support) => {
+                    let formats = if support.formats.is_empty() {
+                        Err("no surface formats".into())
+                    } else {
+                        Ok(format!("{} surface formats", support.formats.len()))
+                    };
+                    checks.push(("swapchain formats", formats));
+
+                    let present_modes = if support.present_modes.is_empty() {
+                        Err("no present modes".into())
+                    } else {
+                        Ok(format!("{:?}", support.present_modes))
+                    };
+                    checks.push(("present modes", present_modes));
+                }
+                Err(e) => checks.push(("swapchain", Err(e.to_string()))),
+            }
+        }
+
+        Self {
+            name: properties.device_name.to_string(),
+            checks,
+        }
+    }
 
-    let features = instance.get_physical_device_features(physical_device);
-    if features.geometry_shader != vk::TRUE {
-        return Err(anyhow!(SuitabilityError(
-            "Missing geometry shader support."
-        )));
+    pub fn is_suitable(&self) -> bool {
+        self.checks.iter().all(|(_, r)| r.is_ok())
     }
 
-    let support = SwapchainSupport::get(instance, data, physical_device)?; //must be after extension check
-    if support.formats.is_empty() || support.present_modes.is_empty() {
-        return Err(anyhow!(SuitabilityError("Insufficient swapchain support.")));
+    /// Fails with every failed check if the device isn't suitable.
+    pub fn check(&self) -> Result<()> {
+        let failures = self
+            .checks
+            .iter()
+            .filter_map(|(name, r)| r.as_ref().err().map(|e| format!("{}: {}", name, e)))
+            .collect::<Vec<_>>();
+
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!(SuitabilityError(failures.join("; "))))
+        }
     }
+}
 
-    Ok(())
+impl fmt::Display for SuitabilityReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let verdict = if self.is_suitable() { "suitable" } else { "unsuitable" };
+        writeln!(f, "`{}`: {}", self.name, verdict)?;
+        for (name, result) in &self.checks {
+            match result {
+                Ok(found) => writeln!(f, "  ok   {}: {}", name, found)?,
+                Err(reason) => writeln!(f, "  FAIL {}: {}", name, reason)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The sample counts supported for both color and depth attachments.
+pub unsafe fn get_supported_msaa_samples(
+    instance: &Instance,
+    data: &AppData,
+) -> vk::SampleCountFlags {
+    let properties = instance.get_physical_device_properties(data.physical_device);
+    properties.limits.framebuffer_color_sample_counts
+        & properties.limits.framebuffer_depth_sample_counts
+}
+
+/// The highest count in `supported` that is no more than `limit`.
+fn highest_sample_count(
+    supported: vk::SampleCountFlags,
+    limit: vk::SampleCountFlags,
+) -> vk::SampleCountFlags {
+    [
+        vk::SampleCountFlags::_64,
+        vk::SampleCountFlags::_32,
+        vk::SampleCountFlags::_16,
+        vk::SampleCountFlags::_8,
+        vk::SampleCountFlags::_4,
+        vk::SampleCountFlags::_2,
+    ]
+    .iter()
+    .cloned()
+    .find(|c| c.bits() <= limit.bits() && supported.contains(*c))
+    .unwrap_or(vk::SampleCountFlags::_1)
+}
+
+/// The sample count requested in the app options, lowered to the highest
+/// supported count below it if the device doesn't support it, or the highest
+/// supported count.
+pub unsafe fn get_msaa_samples(instance: &Instance, data: &AppData) -> vk::SampleCountFlags {
+    let supported = get_supported_msaa_samples(instance, data);
+    let samples = match data.options.msaa_samples {
+        Some(requested) if !supported.contains(requested) => {
+            let fallback = highest_sample_count(supported, requested);
+            warn!(
+                "Requested {:?} MSAA samples but only {:?} are supported; using {:?}.",
+                requested, supported, fallback
+            );
+            fallback
+        }
+        Some(requested) => requested,
+        None => highest_sample_count(supported, vk::SampleCountFlags::_64),
+    };
+
+    info!("Using {:?} MSAA samples.", samples);
+    samples
+}
+
+/// The device extensions required by the app (none when rendering headless).
+pub fn required_device_extensions(data: &AppData) -> &'static [vk::ExtensionName] {
+    if data.headless {
+        &[]
+    } else {
+        crate::DEVICE_EXTENSIONS
+    }
 }
 
 pub unsafe fn check_physical_device_extensions(
     instance: &Instance,
+    data: &AppData,
     physical_device: vk::PhysicalDevice,
 ) -> Result<()> {
     let extensions = instance
@@ -77,44 +456,56 @@ pub unsafe fn check_physical_device_extensions(
         .iter()
         .map(|e| e.extension_name)
         .collect::<HashSet<_>>();
-    if crate::DEVICE_EXTENSIONS.iter().all(|e| extensions.contains(e)) {
+    let missing = required_device_extensions(data)
+        .iter()
+        .filter(|e| !extensions.contains(e))
+        .map(|e| e.to_string())
+        .collect::<Vec<_>>();
+    if missing.is_empty() {
         Ok(())
     } else {
-        Err(anyhow!(SuitabilityError(
-            "Missing required device extensions."
-        )))
+        Err(anyhow!(SuitabilityError(format!(
+            "Missing required device extensions: {}.",
+            missing.join(", ")
+        ))))
     }
 }
 
 pub unsafe fn create_logical_device(
     entry: &Entry,
-    instance: &Instance,
+    instance: &SharedInstance,
     data: &mut AppData,
-) -> Result<Device> {
+) -> Result<SharedDevice> {
     let indices = QueueFamilyIndices::get(instance, data, data.physical_device)?;
 
-    let mut unique_indices = HashSet::new();
-    unique_indices.insert(indices.graphics);
-    unique_indices.insert(indices.present);
-    unique_indices.insert(indices.transfer);
+    // The number of queues needed from each family.
+    let mut queue_counts = BTreeMap::<u32, usize>::new();
+    for (family, queue) in [
+        (indices.graphics, 0),
+        (indices.present, 0),
+        (indices.transfer, indices.transfer_queue),
+    ] {
+        let count = queue_counts.entry(family).or_default();
+        *count = (*count).max(queue as usize + 1);
+    }
 
-    let queue_priorities = &[1.0];
-    let queue_infos = unique_indices
+    let queue_priorities = &[1.0, 1.0];
+    let queue_infos = queue_counts
         .iter()
-        .map(|i| {
+        .map(|(family, count)| {
             vk::DeviceQueueCreateInfo::builder()
-                .queue_family_index(*i)
-                .queue_priorities(queue_priorities)
+                .queue_family_index(*family)
+                .queue_priorities(&queue_priorities[..*count])
         })
         .collect::<Vec<_>>();
 
-    let layers = if crate::VALIDATION_ENABLED {
+    let layers = if data.validation {
         vec![crate::VALIDATION_LAYER.as_ptr()]
     } else {
         vec![]
     };
 
-    let mut extensions = crate::DEVICE_EXTENSIONS
+    let mut extensions = required_device_extensions(data)
         .iter()
         .map(|n| n.as_ptr())
         .collect::<Vec<_>>();
@@ -122,19 +513,68 @@ pub unsafe fn create_logical_device(
         extensions.push(vk::KHR_PORTABILITY_SUBSET_EXTENSION.name.as_ptr());
     }
 
-    let features = vk::PhysicalDeviceFeatures::builder();
+    // Enable every requested feature the device supports. The required ones
+    // were checked when the device was picked.
+    let request = data.options.features;
+    let mut requested = request.required.union(&request.optional);
+    if data.options.min_sample_shading.is_some() {
+        requested.core.sample_rate_shading = vk::TRUE;
+    }
+
+    let supported = get_supported_features(instance, data, data.physical_device);
+    let mut features = requested.intersection(&supported);
+    data.features = features;
+
+    let unavailable = requested.missing_from(&supported);
+    if !unavailable.is_empty() {
+        info!("Optional device features unavailable: {}.", unavailable.join(", "));
+    }
+    debug!("Enabled device features: {}.", data.features);
+
+    if data.options.min_sample_shading.is_some() && features.core.sample_rate_shading != vk::TRUE {
+        warn!("Sample-rate shading requested but not supported.");
+        data.options.min_sample_shading = None;
+    }
 
-    let info = vk::DeviceCreateInfo::builder()
+    let mut info = vk::DeviceCreateInfo::builder()
         .queue_create_infos(&queue_infos)
         .enabled_layer_names(&layers)
         .enabled_extension_names(&extensions)
-        .enabled_features(&features);
+        .enabled_features(&features.core);
+
+    // Unsupported versions have nothing enabled, but their structs still
+    // mustn't be chained.
+    let version = device_api_version(instance, data, data.physical_device);
+    if version >= Version::V1_2_0 {
+        info = info
+            .push_next(&mut features.vulkan11)
+            .push_next(&mut features.vulkan12);
+    }
+    if version >= Version::new(1, 3, 0) {
+        info = info.push_next(&mut features.vulkan13);
+    }
 
     let device = instance.create_device(data.physical_device, &info, None)?;
 
     data.graphics_queue = device.get_device_queue(indices.graphics, 0);
     data.present_queue = device.get_device_queue(indices.present, 0);
-    data.transfer_queue = device.get_device_queue(indices.transfer, 0);
+    data.transfer_queue = device.get_device_queue(indices.transfer, indices.transfer_queue);
+
+    if indices.shared_transfer() {
+        info!(
+            "No dedicated transfer queue family; uploading on graphics queue {}.",
+            indices.transfer_queue
+        );
+    }
+
+    let device = SharedDevice::new(OwnedDevice::new(instance, device));
+    set_object_name(&device, data.graphics_queue, "graphics queue");
+    if data.present_queue != data.graphics_queue {
+        set_object_name(&device, data.present_queue, "present queue");
+    }
+    if data.transfer_queue != data.graphics_queue {
+        set_object_name(&device, data.transfer_queue, "transfer queue");
+    }
 
     Ok(device)
 }
@@ -143,7 +583,18 @@ pub unsafe fn create_logical_device(
 pub struct QueueFamilyIndices {
     pub graphics: u32,
     pub present: u32,
+    /// May be the graphics family when there is no dedicated transfer family.
     pub transfer: u32,
+    /// The index of the transfer queue within its family.
+    pub transfer_queue: u32,
+}
+
+impl QueueFamilyIndices {
+    /// Whether uploads share the graphics family, so resources need no
+    /// ownership transfer between the two.
+    pub fn shared_transfer(&self) -> bool {
+        self.transfer == self.graphics
+    }
 }
 
 impl QueueFamilyIndices {
@@ -154,35 +605,142 @@ impl QueueFamilyIndices {
     ) -> Result<Self> {
         let properties = instance.get_physical_device_queue_family_properties(physical_device);
 
-        let mut present = None;
-        for (index, properties) in properties.iter().enumerate() {
-            if instance.get_physical_device_surface_support_khr(
-                physical_device,
-                index as u32,
-                data.surface,
-            )? {
-                present = Some(index as u32);
-                break;
-            }
-        }
-
         let graphics = properties
             .iter()
             .position(|p| p.queue_flags.contains(vk::QueueFlags::GRAPHICS))
             .map(|i| i as u32);
 
-        let transfer = properties
+        let mut present = None;
+        if data.headless {
+            // Nothing is presented without a surface, so the graphics queue stands in.
+            present = graphics;
+        } else {
+            for (index, properties) in properties.iter().enumerate() {
+                if instance.get_physical_device_surface_support_khr(
+                    physical_device,
+                    index as u32,
+                    *data.surface,
+                )? {
+                    present = Some(index as u32);
+                    break;
+                }
+            }
+        }
+
+        // Prefer a dedicated transfer family, which usually maps to a DMA
+        // engine. Graphics families always support transfers too, so fall back
+        // to a second queue of the graphics family, or share its only queue.
+        let dedicated_transfer = properties
             .iter()
             .position(|p| p.queue_flags.contains(vk::QueueFlags::TRANSFER) && !p.queue_flags.contains(vk::QueueFlags::GRAPHICS))
             .map(|i| i as u32);
 
-        if let (Some(graphics), Some(present), Some(transfer)) = (graphics, present, transfer) {
-            // println!("gfx: {}\ntransfer: {}", graphics, transfer);
-            Ok(Self { graphics, present, transfer })
+        let transfer = dedicated_transfer.map(|t| (t, 0)).or_else(|| {
+            let graphics = graphics?;
+            let queue = if properties[graphics as usize].queue_count > 1 { 1 } else { 0 };
+            Some((graphics, queue))
+        });
+
+        if let (Some(graphics), Some(present), Some((transfer, transfer_queue))) =
+            (graphics, present, transfer)
+        {
+            Ok(Self {
+                graphics,
+                present,
+                transfer,
+                transfer_queue,
+            })
         } else {
-            Err(anyhow!(SuitabilityError(
-                        "Missing required queue families."
-                        )))
+            let missing = [("graphics", graphics), ("present", present)]
+                .iter()
+                .filter(|(_, f)| f.is_none())
+                .map(|(n, _)| *n)
+                .collect::<Vec<_>>();
+            Err(anyhow!(SuitabilityError(format!(
+                "Missing required queue families: {}.",
+                missing.join(", ")
+            ))))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UUID: &str = "00112233-4455-6677-8899-aabbccddeeff";
+
+    fn candidate(index: usize, name: &str) -> DeviceCandidate {
+        DeviceCandidate {
+            index,
+            physical_device: vk::PhysicalDevice::null(),
+            name: name.into(),
+            device_type: vk::PhysicalDeviceType::DISCRETE_GPU,
+            uuid: Some([
+                0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc,
+                0xdd, 0xee, 0xff,
+            ]),
+            memory: 0,
+            score: 0,
+            unsuitable: None,
+            report: SuitabilityReport {
+                name: name.into(),
+                checks: Vec::new(),
+            },
+        }
+    }
+
+    fn parse(s: &str) -> DeviceSelector {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn selector_parses_explicit_index() {
+        assert_eq!(parse("#1"), DeviceSelector::Index(1));
+        assert!(parse("#1").matches(&candidate(1, "GPU")));
+        assert!(!parse("#1").matches(&candidate(0, "GPU")));
+
+        // Out of range indices parse, but match no device.
+        assert!(!parse("#3080").matches(&candidate(0, "GPU")));
+
+        assert!("#".parse::<DeviceSelector>().is_err());
+        assert!("#-1".parse::<DeviceSelector>().is_err());
+        assert!("#one".parse::<DeviceSelector>().is_err());
+        assert!("#99999999999999999999999".parse::<DeviceSelector>().is_err());
+    }
+
+    #[test]
+    fn selector_treats_bare_numbers_as_names() {
+        let gpu = candidate(0, "NVIDIA GeForce RTX 3080");
+
+        assert_eq!(parse("3080"), DeviceSelector::Name("3080".into()));
+        assert!(parse("3080").matches(&gpu));
+        assert!(parse("geforce").matches(&gpu));
+        assert!(!parse("radeon").matches(&gpu));
+    }
+
+    #[test]
+    fn selector_parses_uuids() {
+        let gpu = candidate(0, "GPU");
+
+        assert!(matches!(parse(UUID), DeviceSelector::Uuid(_)));
+        assert!(parse(UUID).matches(&gpu));
+        assert_eq!(parse(&UUID.replace('-', "").to_uppercase()), parse(UUID));
+
+        // Anything that isn't 32 hex digits is a name.
+        let short = &UUID[..UUID.len() - 1];
+        assert_eq!(parse(short), DeviceSelector::Name(short.into()));
+        let not_hex = UUID.replace('a', "g");
+        assert_eq!(parse(&not_hex), DeviceSelector::Name(not_hex.clone()));
+        assert!(!parse(&not_hex).matches(&gpu));
+    }
+
+    #[test]
+    fn selector_display_round_trips() {
+        for s in ["#2", UUID, "3080"] {
+            assert_eq!(parse(s).to_string(), s);
+            assert_eq!(parse(&parse(s).to_string()), parse(s));
         }
+        assert!("".parse::<DeviceSelector>().is_err());
     }
 }
@@ -0,0 +1,203 @@
+use crate::gfx::*;
+use std::fmt;
+use vulkanalia::vk::InstanceV1_1;
+use vulkanalia::Version;
+
+/// A set of device features from Vulkan 1.0 through 1.3.
+///
+/// The 1.1, 1.2 and 1.3 structs are only queried and enabled on devices
+/// supporting those versions, and are otherwise all unsupported.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct DeviceFeatures {
+    pub core: vk::PhysicalDeviceFeatures,
+    pub vulkan11: vk::PhysicalDeviceVulkan11Features,
+    pub vulkan12: vk::PhysicalDeviceVulkan12Features,
+    pub vulkan13: vk::PhysicalDeviceVulkan13Features,
+}
+
+/// The features the application can't run without, and those it will use if
+/// the device supports them.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct FeatureRequest {
+    pub required: DeviceFeatures,
+    pub optional: DeviceFeatures,
+}
+
+macro_rules! features {
+    ($($group:ident { $($field:ident),* $(,)? })*) => {
+        impl DeviceFeatures {
+            /// Calls `f` with the name of every feature and its value in
+            /// `self` and `other`.
+            fn zip(&self, other: &Self, mut f: impl FnMut(&'static str, bool, bool)) {
+                $($(f(
+                    stringify!($field),
+                    self.$group.$field == vk::TRUE,
+                    other.$group.$field == vk::TRUE,
+                );)*)*
+            }
+
+            /// Sets every feature to `f` of its value in `self` and `other`.
+            fn combine(&self, other: &Self, f: impl Fn(bool, bool) -> bool) -> Self {
+                let mut result = *self;
+                $($(
+                    let enabled = f(
+                        self.$group.$field == vk::TRUE,
+                        other.$group.$field == vk::TRUE,
+                    );
+                    result.$group.$field = enabled as vk::Bool32;
+                )*)*
+                result
+            }
+        }
+    };
+}
+
+features! {
+    core {
+        robust_buffer_access, full_draw_index_uint32, image_cube_array, independent_blend,
+        geometry_shader, tessellation_shader, sample_rate_shading, dual_src_blend, logic_op,
+        multi_draw_indirect, draw_indirect_first_instance, depth_clamp, depth_bias_clamp,
+        fill_mode_non_solid, depth_bounds, wide_lines, large_points, alpha_to_one,
+        multi_viewport, sampler_anisotropy, texture_compression_etc2,
+        texture_compression_astc_ldr, texture_compression_bc, occlusion_query_precise,
+        pipeline_statistics_query, vertex_pipeline_stores_and_atomics,
+        fragment_stores_and_atomics, shader_tessellation_and_geometry_point_size,
+        shader_image_gather_extended, shader_storage_image_extended_formats,
+        shader_storage_image_multisample, shader_storage_image_read_without_format,
+        shader_storage_image_write_without_format,
+        shader_uniform_buffer_array_dynamic_indexing,
+        shader_sampled_image_array_dynamic_indexing,
+        shader_storage_buffer_array_dynamic_indexing,
+        shader_storage_image_array_dynamic_indexing, shader_clip_distance,
+        shader_cull_distance, shader_float64, shader_int64, shader_int16,
+        shader_resource_residency, shader_resource_min_lod, sparse_binding,
+        sparse_residency_buffer, sparse_residency_image_2d, sparse_residency_image_3d,
+        sparse_residency2_samples, sparse_residency4_samples, sparse_residency8_samples,
+        sparse_residency16_samples, sparse_residency_aliased, variable_multisample_rate,
+        inherited_queries,
+    }
+    vulkan11 {
+        storage_buffer_16bit_access, uniform_and_storage_buffer_16bit_access,
+        storage_push_constant16, storage_input_output16, multiview,
+        multiview_geometry_shader, multiview_tessellation_shader,
+        variable_pointers_storage_buffer, variable_pointers, protected_memory,
+        sampler_ycbcr_conversion, shader_draw_parameters,
+    }
+    vulkan12 {
+        sampler_mirror_clamp_to_edge, draw_indirect_count, storage_buffer_8bit_access,
+        uniform_and_storage_buffer_8bit_access, storage_push_constant8,
+        shader_buffer_int64_atomics, shader_shared_int64_atomics, shader_float16,
+        shader_int8, descriptor_indexing, shader_input_attachment_array_dynamic_indexing,
+        shader_uniform_texel_buffer_array_dynamic_indexing,
+        shader_storage_texel_buffer_array_dynamic_indexing,
+        shader_uniform_buffer_array_non_uniform_indexing,
+        shader_sampled_image_array_non_uniform_indexing,
+        shader_storage_buffer_array_non_uniform_indexing,
+        shader_storage_image_array_non_uniform_indexing,
+        shader_input_attachment_array_non_uniform_indexing,
+        shader_uniform_texel_buffer_array_non_uniform_indexing,
+        shader_storage_texel_buffer_array_non_uniform_indexing,
+        descriptor_binding_uniform_buffer_update_after_bind,
+        descriptor_binding_sampled_image_update_after_bind,
+        descriptor_binding_storage_image_update_after_bind,
+        descriptor_binding_storage_buffer_update_after_bind,
+        descriptor_binding_uniform_texel_buffer_update_after_bind,
+        descriptor_binding_storage_texel_buffer_update_after_bind,
+        descriptor_binding_update_unused_while_pending, descriptor_binding_partially_bound,
+        descriptor_binding_variable_descriptor_count, runtime_descriptor_array,
+        sampler_filter_minmax, scalar_block_layout, imageless_framebuffer,
+        uniform_buffer_standard_layout, shader_subgroup_extended_types,
+        separate_depth_stencil_layouts, host_query_reset, timeline_semaphore,
+        buffer_device_address, buffer_device_address_capture_replay,
+        buffer_device_address_multi_device, vulkan_memory_model,
+        vulkan_memory_model_device_scope,
+        vulkan_memory_model_availability_visibility_chains, shader_output_viewport_index,
+        shader_output_layer, subgroup_broadcast_dynamic_id,
+    }
+    vulkan13 {
+        robust_image_access, inline_uniform_block,
+        descriptor_binding_inline_uniform_block_update_after_bind,
+        pipeline_creation_cache_control, private_data, shader_demote_to_helper_invocation,
+        shader_terminate_invocation, subgroup_size_control, compute_full_subgroups,
+        synchronization2, texture_compression_astc_hdr,
+        shader_zero_initialize_workgroup_memory, dynamic_rendering,
+        shader_integer_dot_product, maintenance4,
+    }
+}
+
+impl DeviceFeatures {
+    /// The features enabled in either set.
+    pub fn union(&self, other: &Self) -> Self {
+        self.combine(other, |a, b| a || b)
+    }
+
+    /// The features enabled in both sets.
+    pub fn intersection(&self, other: &Self) -> Self {
+        self.combine(other, |a, b| a && b)
+    }
+
+    /// The names of the features enabled here but not in `supported`.
+    pub fn missing_from(&self, supported: &Self) -> Vec<&'static str> {
+        let mut missing = Vec::new();
+        self.zip(supported, |name, wanted, supported| {
+            if wanted && !supported {
+                missing.push(name);
+            }
+        });
+        missing
+    }
+
+    /// The names of the enabled features.
+    pub fn names(&self) -> Vec<&'static str> {
+        self.missing_from(&Self::default())
+    }
+}
+
+impl fmt::Display for DeviceFeatures {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.names().join(", "))
+    }
+}
+
+/// Returns the features supported by a device, leaving out the structs for
+/// API versions the instance or device don't support.
+pub unsafe fn get_supported_features(
+    instance: &Instance,
+    data: &AppData,
+    physical_device: vk::PhysicalDevice,
+) -> DeviceFeatures {
+    let mut features = DeviceFeatures {
+        core: instance.get_physical_device_features(physical_device),
+        ..Default::default()
+    };
+
+    let version = device_api_version(instance, data, physical_device);
+    if version >= Version::V1_2_0 {
+        let mut features2 = vk::PhysicalDeviceFeatures2::builder()
+            .push_next(&mut features.vulkan11)
+            .push_next(&mut features.vulkan12);
+        if version >= Version::new(1, 3, 0) {
+            features2 = features2.push_next(&mut features.vulkan13);
+        }
+        instance.get_physical_device_features2(physical_device, &mut features2);
+    }
+
+    // The structs are copied around, so don't leave them linked together.
+    features.vulkan11.next = std::ptr::null_mut();
+    features.vulkan12.next = std::ptr::null_mut();
+    features.vulkan13.next = std::ptr::null_mut();
+
+    features
+}
+
+/// The API version usable with a device, capped by the instance version.
+pub unsafe fn device_api_version(
+    instance: &Instance,
+    data: &AppData,
+    physical_device: vk::PhysicalDevice,
+) -> Version {
+    let properties = instance.get_physical_device_properties(physical_device);
+    let version = Version::from(properties.api_version);
+    let version = Version::new(version.major, version.minor, 0);
+    version.min(data.api_version)
+}
@@ -0,0 +1,74 @@
+//! GLSL to SPIR-V compilation with naga.
+
+use anyhow::{anyhow, Result};
+use naga::back::spv;
+use naga::front::glsl;
+use naga::valid::{Capabilities, ValidationFlags, Validator};
+use naga::ShaderStage;
+use std::path::Path;
+
+/// Returns the shader stage for a `.vert` or `.frag` file.
+pub fn shader_stage(path: &Path) -> Option<ShaderStage> {
+    match path.extension()?.to_str()? {
+        "vert" => Some(ShaderStage::Vertex),
+        "frag" => Some(ShaderStage::Fragment),
+        _ => None,
+    }
+}
+
+/// Compiles a GLSL shader to SPIR-V bytecode.
+///
+/// Errors are reported as `path:line:column: message`, one per line.
+pub fn compile_glsl(path: &Path) -> Result<Vec<u8>> {
+    let stage = shader_stage(path)
+        .ok_or_else(|| anyhow!("Unknown shader stage for `{}`.", path.display()))?;
+    let source = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("Failed to read `{}`: {}", path.display(), e))?;
+
+    compile_glsl_source(&source, stage, path)
+}
+
+/// Compiles GLSL source for `stage`, reporting errors against `path`.
+///
+/// naga has no `sampler2D`, so combined image samplers are declared as a
+/// `texture2D` and a `sampler` sharing one binding.
+pub fn compile_glsl_source(source: &str, stage: ShaderStage, path: &Path) -> Result<Vec<u8>> {
+    let module = glsl::Frontend::default()
+        .parse(&glsl::Options::from(stage), source)
+        .map_err(|errors| {
+            let messages = errors
+                .errors
+                .iter()
+                .map(|e| match e.location(source) {
+                    Some(l) => format!(
+                        "{}:{}:{}: {}",
+                        path.display(),
+                        l.line_number,
+                        l.line_position,
+                        e.kind
+                    ),
+                    None => format!("{}: {}", path.display(), e.kind),
+                })
+                .collect::<Vec<_>>();
+            anyhow!(messages.join("\n"))
+        })?;
+
+    // Descriptor bindings are checked against the pipeline layout by Vulkan.
+    let flags = ValidationFlags::all() - ValidationFlags::BINDINGS;
+    let info = Validator::new(flags, Capabilities::all())
+        .validate(&module)
+        .map_err(|e| anyhow!(e.emit_to_string_with_path(source, &path.display().to_string())))?;
+
+    // The shaders already account for Vulkan's coordinate space.
+    let mut options = spv::Options::default();
+    options.flags.remove(spv::WriterFlags::ADJUST_COORDINATE_SPACE);
+    let pipeline_options = spv::PipelineOptions {
+        shader_stage: stage,
+        entry_point: "main".into(),
+    };
+
+    let words = spv::write_vec(&module, &info, &options, Some(&pipeline_options))
+        .map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+
+    Ok(words.iter().flat_map(|w| w.to_le_bytes()).collect())
+}
@@ -0,0 +1,228 @@
+use crate::gfx::app::App;
+use crate::gfx::offscreen::Frame;
+use crate::gfx::validation::ValidationOptions;
+use crate::gfx::AppOptions;
+use anyhow::{anyhow, Result};
+use log::*;
+use std::fs::{self, File};
+use std::path::Path;
+
+/// Set to regenerate the reference images instead of comparing against them.
+pub const BLESS_VAR: &str = "VK_TEST_BLESS";
+
+/// The largest possible YIQ difference between two RGB pixels.
+const MAX_YIQ_DELTA: f32 = 35215.0;
+
+/// How far a rendered frame may drift from its reference image.
+#[derive(Copy, Clone, Debug)]
+pub struct Tolerance {
+    /// Channel differences up to this value are ignored outright.
+    pub channel: u8,
+    /// Pixels beyond `channel` still match if their perceptual (YIQ)
+    /// difference is below this threshold, in `0.0..=1.0`.
+    pub perceptual: f32,
+    /// The fraction of pixels allowed to mismatch.
+    pub max_mismatched: f64,
+}
+
+impl Default for Tolerance {
+    fn default() -> Self {
+        Self {
+            channel: 2,
+            perceptual: 0.05,
+            max_mismatched: 0.001,
+        }
+    }
+}
+
+/// The result of comparing a frame against its reference image.
+#[derive(Clone, Debug)]
+pub struct Comparison {
+    pub mismatched: usize,
+    pub total: usize,
+    pub max_channel_delta: u8,
+    /// Mismatched pixels in red, tolerated differences in yellow and a faded
+    /// copy of the reference everywhere else.
+    pub diff: Frame,
+}
+
+impl Comparison {
+    pub fn passes(&self, tolerance: &Tolerance) -> bool {
+        self.mismatched as f64 <= self.total as f64 * tolerance.max_mismatched
+    }
+}
+
+/// Returns whether this machine has a Vulkan loader, a driver and a
+/// physical device that can render headless frames.
+pub fn vulkan_device_available() -> bool {
+    let options = AppOptions {
+        validation: ValidationOptions {
+            enabled: false,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    match unsafe { App::list_devices(options) } {
+        Ok(devices) => devices.iter().any(|d| d.unsuitable.is_none()),
+        Err(e) => {
+            debug!("No Vulkan device available: {}", e);
+            false
+        }
+    }
+}
+
+/// Renders `frames` headless frames with a fixed clock advancing by
+/// `time_step` seconds per frame and reads back the last one.
+pub unsafe fn render_frames(app: &mut App, frames: u32, time_step: f32) -> Result<Frame> {
+    for i in 0..frames {
+        app.fixed_time = Some(i as f32 * time_step);
+        app.render_headless()?;
+    }
+
+    app.read_frame()
+}
+
+/// Compares `actual` against `expected` pixel by pixel.
+pub fn compare(actual: &Frame, expected: &Frame, tolerance: &Tolerance) -> Result<Comparison> {
+    if (actual.width, actual.height) != (expected.width, expected.height) {
+        return Err(anyhow!(
+            "Frame size {}x{} does not match reference size {}x{}.",
+            actual.width,
+            actual.height,
+            expected.width,
+            expected.height
+        ));
+    }
+
+    let threshold = MAX_YIQ_DELTA * tolerance.perceptual * tolerance.perceptual;
+
+    let mut mismatched = 0;
+    let mut max_channel_delta = 0;
+    let mut diff = Vec::with_capacity(expected.pixels.len());
+    for (a, e) in actual.pixels.chunks(4).zip(expected.pixels.chunks(4)) {
+        let delta = a.iter().zip(e).map(|(a, e)| a.abs_diff(*e)).max().unwrap();
+        max_channel_delta = max_channel_delta.max(delta);
+
+        if delta <= tolerance.channel {
+            let y = yiq(e).0 as u8;
+            diff.extend_from_slice(&[y / 4 + 191, y / 4 + 191, y / 4 + 191, 255]);
+        } else if yiq_delta(a, e) <= threshold {
+            diff.extend_from_slice(&[255, 255, 0, 255]);
+        } else {
+            mismatched += 1;
+            diff.extend_from_slice(&[255, 0, 0, 255]);
+        }
+    }
+
+    Ok(Comparison {
+        mismatched,
+        total: (expected.width * expected.height) as usize,
+        max_channel_delta,
+        diff: Frame {
+            width: expected.width,
+            height: expected.height,
+            pixels: diff,
+        },
+    })
+}
+
+/// Checks `actual` against `golden_dir/<name>.png`.
+///
+/// When [`BLESS_VAR`] is set the reference is overwritten instead. On failure
+/// the actual and diff images are written to `output_dir`.
+pub fn check_golden(
+    name: &str,
+    actual: &Frame,
+    golden_dir: &Path,
+    output_dir: &Path,
+    tolerance: &Tolerance,
+) -> Result<()> {
+    let reference = golden_dir.join(format!("{}.png", name));
+
+    if std::env::var_os(BLESS_VAR).is_some() {
+        fs::create_dir_all(golden_dir)?;
+        actual.save_png(&reference)?;
+        info!("Blessed golden image `{}`.", reference.display());
+        return Ok(());
+    }
+
+    if !reference.exists() {
+        return Err(anyhow!(
+            "Missing golden image `{}` (rerun with {}=1 to create it).",
+            reference.display(),
+            BLESS_VAR
+        ));
+    }
+
+    let expected = load_png(&reference)?;
+    let comparison = compare(actual, &expected, tolerance)?;
+    if comparison.passes(tolerance) {
+        return Ok(());
+    }
+
+    fs::create_dir_all(output_dir)?;
+    let actual_path = output_dir.join(format!("{}.actual.png", name));
+    let diff_path = output_dir.join(format!("{}.diff.png", name));
+    actual.save_png(&actual_path)?;
+    comparison.diff.save_png(&diff_path)?;
+
+    Err(anyhow!(
+        "Golden image `{}` mismatch: {} of {} pixels differ (max channel delta {}). \
+         Wrote `{}` and `{}`.",
+        name,
+        comparison.mismatched,
+        comparison.total,
+        comparison.max_channel_delta,
+        actual_path.display(),
+        diff_path.display()
+    ))
+}
+
+/// Loads a PNG as tightly packed RGBA8 pixels.
+pub fn load_png(path: &Path) -> Result<Frame> {
+    let mut decoder = png::Decoder::new(File::open(path)?);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info()?;
+
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer)?;
+    buffer.truncate(info.buffer_size());
+
+    let pixels = match info.color_type {
+        png::ColorType::Rgba => buffer,
+        png::ColorType::Rgb => buffer
+            .chunks(3)
+            .flat_map(|p| [p[0], p[1], p[2], 255])
+            .collect(),
+        png::ColorType::GrayscaleAlpha => buffer
+            .chunks(2)
+            .flat_map(|p| [p[0], p[0], p[0], p[1]])
+            .collect(),
+        png::ColorType::Grayscale => buffer.iter().flat_map(|p| [*p, *p, *p, 255]).collect(),
+        png::ColorType::Indexed => unreachable!("expanded by normalize_to_color8"),
+    };
+
+    Ok(Frame {
+        width: info.width,
+        height: info.height,
+        pixels,
+    })
+}
+
+fn yiq(pixel: &[u8]) -> (f32, f32, f32) {
+    let (r, g, b) = (pixel[0] as f32, pixel[1] as f32, pixel[2] as f32);
+    (
+        r * 0.298_895_3 + g * 0.586_622_5 + b * 0.114_482_23,
+        r * 0.595_977_99 - g * 0.274_176_1 - b * 0.321_801_9,
+        r * 0.211_470_17 - g * 0.522_617_1 + b * 0.311_146_94,
+    )
+}
+
+/// The perceptual difference between two pixels (Kotsarenko & Ramos, 2010).
+fn yiq_delta(a: &[u8], b: &[u8]) -> f32 {
+    let (ay, ai, aq) = yiq(a);
+    let (by, bi, bq) = yiq(b);
+    let (y, i, q) = (ay - by, ai - bi, aq - bq);
+    0.5053 * y * y + 0.299 * i * i + 0.1957 * q * q
+}
@@ -0,0 +1,527 @@
+use crate::gfx::resource::SharedDevice;
+use anyhow::{anyhow, Result};
+use log::*;
+use std::fmt;
+use std::os::raw::c_void;
+use vulkanalia::prelude::v1_0::*;
+use vulkanalia::vk::DeviceV1_1;
+use vulkanalia::Version;
+
+/// The size of the device memory blocks suballocations are carved from.
+const BLOCK_SIZE: vk::DeviceSize = 64 * 1024 * 1024;
+
+/// Resources at least this large get a dedicated device memory allocation,
+/// whether or not the driver asks for one.
+const DEDICATED_THRESHOLD: vk::DeviceSize = BLOCK_SIZE / 4;
+
+/// Whether a resource is linear (buffers, linear images) or optimally tiled.
+///
+/// The two kinds are kept in separate blocks so neighbouring suballocations
+/// never need padding for `bufferImageGranularity`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ResourceKind {
+    Linear,
+    Optimal,
+}
+
+/// The resource memory is allocated for.
+#[derive(Copy, Clone, Debug)]
+pub enum Resource {
+    Buffer(vk::Buffer),
+    Image(vk::Image),
+}
+
+/// A range of device memory owned by one resource.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Allocation {
+    pub memory: vk::DeviceMemory,
+    pub offset: vk::DeviceSize,
+    pub size: vk::DeviceSize,
+    /// The block this was suballocated from, or `None` for dedicated memory.
+    block: Option<u64>,
+}
+
+/// Memory usage reported by an [`Allocator`].
+#[derive(Copy, Clone, Debug, Default)]
+pub struct AllocatorStats {
+    /// Live `vkAllocateMemory` allocations (blocks and dedicated).
+    pub device_allocations: u32,
+    pub blocks: u32,
+    pub dedicated: u32,
+    pub suballocations: u32,
+    /// Bytes of device memory allocated from the driver.
+    pub allocated_bytes: vk::DeviceSize,
+    /// Bytes handed out to resources.
+    pub used_bytes: vk::DeviceSize,
+}
+
+impl fmt::Display for AllocatorStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} device allocations ({} blocks, {} dedicated), {} suballocations, {:.1} of {:.1} MiB used",
+            self.device_allocations,
+            self.blocks,
+            self.dedicated,
+            self.suballocations,
+            self.used_bytes as f64 / (1024.0 * 1024.0),
+            self.allocated_bytes as f64 / (1024.0 * 1024.0),
+        )
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Block {
+    id: u64,
+    memory: vk::DeviceMemory,
+    size: vk::DeviceSize,
+    mapped: *mut c_void,
+    /// Free `(offset, size)` ranges sorted by offset.
+    free: Vec<(vk::DeviceSize, vk::DeviceSize)>,
+    suballocations: u32,
+}
+
+impl Block {
+    fn used(&self) -> vk::DeviceSize {
+        self.size - self.free.iter().map(|(_, size)| size).sum::<vk::DeviceSize>()
+    }
+
+    /// Carves an aligned range out of the first free range large enough.
+    fn allocate(
+        &mut self,
+        size: vk::DeviceSize,
+        alignment: vk::DeviceSize,
+    ) -> Option<vk::DeviceSize> {
+        let (index, offset) = self.free.iter().enumerate().find_map(|(i, (start, len))| {
+            let offset = align_up(*start, alignment);
+            (offset + size <= start + len).then_some((i, offset))
+        })?;
+
+        let (start, len) = self.free.remove(index);
+        if offset + size < start + len {
+            self.free.insert(index, (offset + size, start + len - offset - size));
+        }
+        if offset > start {
+            self.free.insert(index, (start, offset - start));
+        }
+
+        self.suballocations += 1;
+        Some(offset)
+    }
+
+    /// Returns a range to the free list, merging it with its neighbours.
+    fn free(&mut self, offset: vk::DeviceSize, size: vk::DeviceSize) {
+        let index = self.free.partition_point(|(start, _)| *start < offset);
+        self.free.insert(index, (offset, size));
+
+        if index + 1 < self.free.len() && offset + size == self.free[index + 1].0 {
+            self.free[index].1 += self.free.remove(index + 1).1;
+        }
+        if index > 0 && self.free[index - 1].0 + self.free[index - 1].1 == offset {
+            self.free[index - 1].1 += self.free.remove(index).1;
+        }
+
+        self.suballocations -= 1;
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Dedicated {
+    memory: vk::DeviceMemory,
+    size: vk::DeviceSize,
+    mapped: *mut c_void,
+}
+
+/// Suballocates resources from large per-memory-type blocks instead of
+/// calling `vkAllocateMemory` for each one.
+///
+/// Every block is freed when the allocator is dropped, which happens once the
+/// last [`Buffer`](crate::gfx::resource::Buffer) or
+/// [`Image`](crate::gfx::resource::Image) using it is gone.
+#[derive(Debug, Default)]
+pub struct Allocator {
+    device: Option<SharedDevice>,
+    memory_properties: vk::PhysicalDeviceMemoryProperties,
+    max_allocations: u32,
+    /// Whether the Vulkan 1.1 dedicated allocation queries are available.
+    dedicated_queries: bool,
+    /// Blocks by `memory_type * 2 + kind`.
+    pools: Vec<Vec<Block>>,
+    dedicated: Vec<Dedicated>,
+    next_block_id: u64,
+}
+
+impl Allocator {
+    pub unsafe fn new(
+        instance: &Instance,
+        physical_device: vk::PhysicalDevice,
+        device: &SharedDevice,
+        version: Version,
+    ) -> Self {
+        let memory_properties = instance.get_physical_device_memory_properties(physical_device);
+        let properties = instance.get_physical_device_properties(physical_device);
+
+        Self {
+            device: Some(device.clone()),
+            memory_properties,
+            max_allocations: properties.limits.max_memory_allocation_count,
+            dedicated_queries: version >= Version::V1_1_0,
+            pools: vec![Vec::new(); memory_properties.memory_type_count as usize * 2],
+            dedicated: Vec::new(),
+            next_block_id: 0,
+        }
+    }
+
+    /// The device memory is allocated from.
+    pub fn device(&self) -> Result<SharedDevice> {
+        self.device
+            .clone()
+            .ok_or_else(|| anyhow!("Allocator has no device."))
+    }
+
+    pub fn memory_type_index(
+        &self,
+        properties: vk::MemoryPropertyFlags,
+        requirements: vk::MemoryRequirements,
+    ) -> Result<u32> {
+        (0..self.memory_properties.memory_type_count)
+            .find(|i| {
+                let suitable = (requirements.memory_type_bits & (1 << i)) != 0;
+                let memory_type = self.memory_properties.memory_types[*i as usize];
+                suitable && memory_type.property_flags.contains(properties)
+            })
+            .ok_or_else(|| anyhow!("Failed to find suitable memory type."))
+    }
+
+    /// Allocates memory for a resource, suballocating it from a shared block
+    /// unless the driver asks for a dedicated allocation or it is large enough
+    /// to deserve one.
+    pub unsafe fn allocate(
+        &mut self,
+        resource: Resource,
+        properties: vk::MemoryPropertyFlags,
+        kind: ResourceKind,
+    ) -> Result<Allocation> {
+        let (requirements, wants_dedicated) = self.memory_requirements(resource)?;
+        let memory_type = self.memory_type_index(properties, requirements)?;
+
+        if wants_dedicated || requirements.size >= DEDICATED_THRESHOLD {
+            let dedicated = self.dedicated_queries.then_some(resource);
+            let (memory, mapped) =
+                self.allocate_memory(memory_type, requirements.size, dedicated)?;
+            self.dedicated.push(Dedicated {
+                memory,
+                size: requirements.size,
+                mapped,
+            });
+            return Ok(Allocation {
+                memory,
+                offset: 0,
+                size: requirements.size,
+                block: None,
+            });
+        }
+
+        let pool = memory_type as usize * 2 + kind as usize;
+        for block in &mut self.pools[pool] {
+            if let Some(offset) = block.allocate(requirements.size, requirements.alignment) {
+                return Ok(Allocation {
+                    memory: block.memory,
+                    offset,
+                    size: requirements.size,
+                    block: Some(block.id),
+                });
+            }
+        }
+
+        let heap = self.memory_properties.memory_types[memory_type as usize].heap_index;
+        let heap_size = self.memory_properties.memory_heaps[heap as usize].size;
+        let size = BLOCK_SIZE.min(heap_size / 8).max(requirements.size);
+
+        let (memory, mapped) = self.allocate_memory(memory_type, size, None)?;
+        let mut block = Block {
+            id: self.next_block_id,
+            memory,
+            size,
+            mapped,
+            free: vec![(0, size)],
+            suballocations: 0,
+        };
+        self.next_block_id += 1;
+
+        debug!(
+            "Allocated {} MiB block for memory type {} ({:?}).",
+            size / (1024 * 1024),
+            memory_type,
+            kind
+        );
+
+        let offset = block
+            .allocate(requirements.size, requirements.alignment)
+            .unwrap();
+        let allocation = Allocation {
+            memory,
+            offset,
+            size: requirements.size,
+            block: Some(block.id),
+        };
+        self.pools[pool].push(block);
+
+        Ok(allocation)
+    }
+
+    /// Returns an allocation to its block, releasing the block if it is empty
+    /// and not the last one of its pool.
+    pub unsafe fn free(&mut self, allocation: Allocation) {
+        let Ok(device) = self.device() else {
+            return;
+        };
+
+        let Some(id) = allocation.block else {
+            if let Some(index) = self
+                .dedicated
+                .iter()
+                .position(|d| d.memory == allocation.memory)
+            {
+                self.dedicated.swap_remove(index);
+                device.free_memory(allocation.memory, None);
+            }
+            return;
+        };
+
+        for pool in &mut self.pools {
+            if let Some(index) = pool.iter().position(|b| b.id == id) {
+                pool[index].free(allocation.offset, allocation.size);
+                if pool[index].suballocations == 0 && pool.len() > 1 {
+                    let block = pool.swap_remove(index);
+                    device.free_memory(block.memory, None);
+                }
+                return;
+            }
+        }
+    }
+
+    /// Returns a pointer to an allocation in persistently mapped host-visible
+    /// memory.
+    pub fn mapped_ptr(&self, allocation: &Allocation) -> Result<*mut c_void> {
+        let mapped = match allocation.block {
+            Some(id) => self
+                .pools
+                .iter()
+                .flatten()
+                .find(|b| b.id == id)
+                .map(|b| b.mapped),
+            None => self
+                .dedicated
+                .iter()
+                .find(|d| d.memory == allocation.memory)
+                .map(|d| d.mapped),
+        };
+
+        match mapped {
+            Some(mapped) if !mapped.is_null() => {
+                Ok(unsafe { mapped.cast::<u8>().add(allocation.offset as usize) }.cast())
+            }
+            _ => Err(anyhow!("Allocation is not host visible.")),
+        }
+    }
+
+    pub fn stats(&self) -> AllocatorStats {
+        let blocks = self.pools.iter().flatten();
+        let dedicated_bytes = self.dedicated.iter().map(|d| d.size).sum::<vk::DeviceSize>();
+
+        AllocatorStats {
+            device_allocations: self.device_allocations(),
+            blocks: blocks.clone().count() as u32,
+            dedicated: self.dedicated.len() as u32,
+            suballocations: blocks.clone().map(|b| b.suballocations).sum(),
+            allocated_bytes: blocks.clone().map(|b| b.size).sum::<vk::DeviceSize>()
+                + dedicated_bytes,
+            used_bytes: blocks.map(|b| b.used()).sum::<vk::DeviceSize>() + dedicated_bytes,
+        }
+    }
+
+    fn device_allocations(&self) -> u32 {
+        (self.pools.iter().map(|p| p.len()).sum::<usize>() + self.dedicated.len()) as u32
+    }
+
+    /// Returns the memory requirements of a resource and whether the driver
+    /// prefers or requires it to have a dedicated allocation.
+    unsafe fn memory_requirements(
+        &self,
+        resource: Resource,
+    ) -> Result<(vk::MemoryRequirements, bool)> {
+        let device = self.device()?;
+
+        if !self.dedicated_queries {
+            let requirements = match resource {
+                Resource::Buffer(buffer) => device.get_buffer_memory_requirements(buffer),
+                Resource::Image(image) => device.get_image_memory_requirements(image),
+            };
+            return Ok((requirements, false));
+        }
+
+        let mut dedicated = vk::MemoryDedicatedRequirements::default();
+        let mut requirements = vk::MemoryRequirements2::builder().push_next(&mut dedicated);
+        match resource {
+            Resource::Buffer(buffer) => {
+                let info = vk::BufferMemoryRequirementsInfo2::builder().buffer(buffer);
+                device.get_buffer_memory_requirements2(&info, &mut requirements);
+            }
+            Resource::Image(image) => {
+                let info = vk::ImageMemoryRequirementsInfo2::builder().image(image);
+                device.get_image_memory_requirements2(&info, &mut requirements);
+            }
+        }
+        let requirements = requirements.memory_requirements;
+
+        let wants_dedicated = dedicated.prefers_dedicated_allocation == vk::TRUE
+            || dedicated.requires_dedicated_allocation == vk::TRUE;
+
+        Ok((requirements, wants_dedicated))
+    }
+
+    /// Allocates device memory, mapping it when it is host visible.
+    ///
+    /// Memory allocated for a single resource is tied to it with
+    /// `VkMemoryDedicatedAllocateInfo`.
+    unsafe fn allocate_memory(
+        &mut self,
+        memory_type: u32,
+        size: vk::DeviceSize,
+        dedicated: Option<Resource>,
+    ) -> Result<(vk::DeviceMemory, *mut c_void)> {
+        let device = self.device()?;
+
+        if self.device_allocations() >= self.max_allocations {
+            return Err(anyhow!(
+                "Reached the device limit of {} memory allocations.",
+                self.max_allocations
+            ));
+        }
+
+        let mut dedicated_info = vk::MemoryDedicatedAllocateInfo::builder();
+        match dedicated {
+            Some(Resource::Buffer(buffer)) => dedicated_info = dedicated_info.buffer(buffer),
+            Some(Resource::Image(image)) => dedicated_info = dedicated_info.image(image),
+            None => {}
+        }
+
+        let mut info = vk::MemoryAllocateInfo::builder()
+            .allocation_size(size)
+            .memory_type_index(memory_type);
+        if dedicated.is_some() {
+            info = info.push_next(&mut dedicated_info);
+        }
+
+        let memory = device.allocate_memory(&info, None)?;
+
+        let flags = self.memory_properties.memory_types[memory_type as usize].property_flags;
+        let mapped = if flags.contains(vk::MemoryPropertyFlags::HOST_VISIBLE) {
+            match device.map_memory(memory, 0, size, vk::MemoryMapFlags::empty()) {
+                Ok(mapped) => mapped,
+                Err(e) => {
+                    device.free_memory(memory, None);
+                    return Err(e.into());
+                }
+            }
+        } else {
+            std::ptr::null_mut()
+        };
+
+        Ok((memory, mapped))
+    }
+}
+
+impl Drop for Allocator {
+    fn drop(&mut self) {
+        let Some(device) = self.device.take() else {
+            return;
+        };
+
+        let stats = self.stats();
+        if stats.suballocations > 0 || stats.dedicated > 0 {
+            warn!("Destroying allocator with live allocations: {}.", stats);
+        }
+
+        unsafe {
+            for block in self.pools.iter_mut().flat_map(|p| p.drain(..)) {
+                device.free_memory(block.memory, None);
+            }
+            for dedicated in self.dedicated.drain(..) {
+                device.free_memory(dedicated.memory, None);
+            }
+        }
+    }
+}
+
+fn align_up(value: vk::DeviceSize, alignment: vk::DeviceSize) -> vk::DeviceSize {
+    value.div_ceil(alignment.max(1)) * alignment.max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(size: vk::DeviceSize) -> Block {
+        Block {
+            id: 0,
+            memory: vk::DeviceMemory::null(),
+            size,
+            mapped: std::ptr::null_mut(),
+            free: vec![(0, size)],
+            suballocations: 0,
+        }
+    }
+
+    #[test]
+    fn allocate_pads_to_alignment() {
+        let mut block = block(1024);
+
+        assert_eq!(block.allocate(10, 1), Some(0));
+        assert_eq!(block.allocate(100, 256), Some(256));
+
+        // The padding before the aligned range stays free.
+        assert_eq!(block.free, vec![(10, 246), (356, 668)]);
+        assert_eq!(block.used(), 110);
+        assert_eq!(block.suballocations, 2);
+
+        // Small enough allocations still fit in the padding.
+        assert_eq!(block.allocate(200, 16), Some(16));
+    }
+
+    #[test]
+    fn free_merges_adjacent_ranges() {
+        let mut block = block(300);
+        let a = block.allocate(100, 1).unwrap();
+        let b = block.allocate(100, 1).unwrap();
+        let c = block.allocate(100, 1).unwrap();
+        assert!(block.free.is_empty());
+
+        block.free(a, 100);
+        block.free(c, 100);
+        assert_eq!(block.free, vec![(0, 100), (200, 100)]);
+
+        // Freeing the middle range merges it with both neighbours.
+        block.free(b, 100);
+        assert_eq!(block.free, vec![(0, 300)]);
+        assert_eq!(block.suballocations, 0);
+        assert_eq!(block.used(), 0);
+    }
+
+    #[test]
+    fn allocate_fails_when_exhausted() {
+        let mut block = block(256);
+
+        assert_eq!(block.allocate(200, 1), Some(0));
+        assert_eq!(block.allocate(100, 1), None);
+
+        // The remaining 56 bytes fit, but not once aligned to 64.
+        assert_eq!(block.allocate(50, 64), None);
+        assert_eq!(block.allocate(56, 8), Some(200));
+        assert_eq!(block.allocate(1, 1), None);
+
+        block.free(0, 200);
+        assert_eq!(block.allocate(100, 1), Some(0));
+    }
+}
@@ -1,72 +1,148 @@
-use anyhow::{anyhow, Result};
+use anyhow::Result;
 use log::*;
 use std::collections::HashSet;
-use std::ffi::CStr;
 use std::os::raw::c_void;
+use std::path::PathBuf;
+use std::sync::Arc;
 use vulkanalia::prelude::v1_0::*;
 use vulkanalia::vk;
 use vulkanalia::vk::ExtDebugUtilsExtension;
+use vulkanalia::Version;
 use vulkanalia::window as vk_window;
 use winit::window::Window;
 
+use self::resource::{
+    Buffer, Image, Owned, OwnedInstance, SharedAllocator, SharedInstance, Surface,
+};
+
 pub mod app;
+pub mod camera;
+pub mod debug;
 pub mod device;
+pub mod features;
+pub mod glsl;
+pub mod golden;
+pub mod memory;
+pub mod model;
+pub mod offscreen;
 pub mod pipeline;
+pub mod pipeline_cache;
+pub mod reflect;
+pub mod resource;
+pub mod shader;
 pub mod swapchain;
+pub mod validation;
 pub mod vertex;
 pub mod texture;
 
 /// The Vulkan handles and associated properties used by our Vulkan app.
-#[derive(Clone, Debug, Default)]
+///
+/// Owned resources are destroyed when dropped, and fields are dropped in
+/// declaration order, so anything that depends on another resource (like the
+/// swapchain on the surface) is declared first.
+#[derive(Debug, Default)]
 pub struct AppData {
-    messenger: vk::DebugUtilsMessengerEXT,
     physical_device: vk::PhysicalDevice,
     graphics_queue: vk::Queue,
     present_queue: vk::Queue,
     transfer_queue: vk::Queue,
-    surface: vk::SurfaceKHR,
-    swapchain: vk::SwapchainKHR,
-    swapchain_images: Vec<vk::Image>,
-    swapchain_format: vk::Format,
-    swapchain_extent: vk::Extent2D,
-    swapchain_image_views: Vec<vk::ImageView>,
-    render_pass: vk::RenderPass,
-    descriptor_set_layout: vk::DescriptorSetLayout,
-    pipeline_layout: vk::PipelineLayout,
-    pipeline: vk::Pipeline,
-    framebuffers: Vec<vk::Framebuffer>,
-    command_pool: vk::CommandPool,
-    transfer_command_pool: vk::CommandPool,
+    /// The features enabled on the logical device.
+    features: features::DeviceFeatures,
+    /// One per frame in flight, re-recorded every frame and freed with its
+    /// pool in `frame_command_pools`.
     command_buffers: Vec<vk::CommandBuffer>,
-    image_available_semaphore: vk::Semaphore,
-    render_finished_semaphore: vk::Semaphore,
-    image_available_semaphores: Vec<vk::Semaphore>,
-    render_finished_semaphores: Vec<vk::Semaphore>,
-    in_flight_fences: Vec<vk::Fence>,
+    frame_command_pools: Vec<Owned<vk::CommandPool>>,
+    image_available_semaphores: Vec<Owned<vk::Semaphore>>,
+    render_finished_semaphores: Vec<Owned<vk::Semaphore>>,
+    in_flight_fences: Vec<Owned<vk::Fence>>,
     images_in_flight: Vec<vk::Fence>,
-    vertex_buffer: vk::Buffer,
-    vertex_buffer_memory: vk::DeviceMemory,
-    index_buffer: vk::Buffer,
-    index_buffer_memory: vk::DeviceMemory,
-    uniform_buffers: Vec<vk::Buffer>,
-    uniform_buffers_memory: Vec<vk::DeviceMemory>,
-    descriptor_pool: vk::DescriptorPool,
+    command_pool: Owned<vk::CommandPool>,
+    transfer_command_pool: Owned<vk::CommandPool>,
+    descriptor_pool: Owned<vk::DescriptorPool>,
     descriptor_sets: Vec<vk::DescriptorSet>,
-    texture_image: vk::Image,
-    texture_image_memory: vk::DeviceMemory,
+    uniform_buffers: Vec<Buffer>,
+    meshes: Vec<model::Mesh>,
+    materials: Vec<model::Material>,
+    framebuffers: Vec<Owned<vk::Framebuffer>>,
+    pipeline: Owned<vk::Pipeline>,
+    pipeline_cache: Owned<vk::PipelineCache>,
+    pipeline_layout: Owned<vk::PipelineLayout>,
+    /// The layout the pipeline's shaders expect, reflected from their SPIR-V.
+    reflection: reflect::PipelineReflection,
+    shaders: shader::Shaders,
+    render_pass: Owned<vk::RenderPass>,
+    descriptor_set_layout: Owned<vk::DescriptorSetLayout>,
+    texture_sampler: Owned<vk::Sampler>,
+    sampler_options: texture::SamplerOptions,
+    texture_image_view: Owned<vk::ImageView>,
+    texture_image: Image,
+    texture_mip_levels: u32,
+    msaa_samples: vk::SampleCountFlags,
+    color_image_view: Owned<vk::ImageView>,
+    color_image: Image,
+    depth_image_view: Owned<vk::ImageView>,
+    depth_image: Image,
+    swapchain_image_views: Vec<Owned<vk::ImageView>>,
+    /// Owned by the swapchain, or by `offscreen_images` when headless.
+    swapchain_images: Vec<vk::Image>,
+    offscreen_images: Vec<Image>,
+    swapchain_format: vk::Format,
+    swapchain_extent: vk::Extent2D,
+    /// The present mode chosen for the swapchain.
+    present_mode: vk::PresentModeKHR,
+    swapchain: Owned<vk::SwapchainKHR>,
+    surface: Surface,
+    allocator: SharedAllocator,
+    headless: bool,
+    /// Whether the validation layer was loaded.
+    validation: bool,
+    /// Shared with the debug messenger, which outlives the app data.
+    messages: Arc<validation::MessageCollector>,
+    /// The API version of the instance, which caps what devices may use.
+    api_version: Version,
+    options: AppOptions,
+}
+
+/// Settings chosen by the application when creating an [`app::App`].
+#[derive(Clone, Debug, Default)]
+pub struct AppOptions {
+    /// Overrides the MSAA sample count, which otherwise defaults to the
+    /// highest count supported for both color and depth attachments.
+    pub msaa_samples: Option<vk::SampleCountFlags>,
+    /// Enables sample-rate shading with this minimum fraction of samples
+    /// shaded, if the device supports it.
+    pub min_sample_shading: Option<f32>,
+    /// Watches this directory of GLSL sources and rebuilds the pipeline
+    /// whenever one of its shaders changes.
+    pub shader_dir: Option<PathBuf>,
+    /// Loads the pipeline cache from this file and saves it back when the
+    /// app is dropped.
+    pub pipeline_cache_path: Option<PathBuf>,
+    /// Device features the app needs or can make use of.
+    pub features: features::FeatureRequest,
+    /// Picks this physical device instead of the highest scoring one. See
+    /// [`device::DeviceSelector`] for the accepted forms.
+    pub device: Option<device::DeviceSelector>,
+    pub validation: validation::ValidationOptions,
+    /// Present modes in order of preference. Mailbox, then vsync, if empty.
+    pub present_modes: Vec<swapchain::PresentMode>,
 }
 
 pub unsafe fn create_instance(
-    window: &Window,
+    window: Option<&Window>,
     entry: &Entry,
     data: &mut AppData,
-) -> Result<Instance> {
+) -> Result<SharedInstance> {
+    // Older loaders reject instances asking for a newer API version.
+    let api_version = entry.version()?.min(crate::MAX_API_VERSION);
+    data.api_version = api_version;
+
     let application_info = vk::ApplicationInfo::builder()
         .application_name(b"VK Test\0")
         .application_version(vk::make_version(1, 0, 0))
         .engine_name(b"No Engine\0")
         .engine_version(vk::make_version(1, 0, 0))
-        .api_version(vk::make_version(1, 0, 0));
+        .api_version(vk::make_version(api_version.major, api_version.minor, 0));
 
     let available_layers = entry
         .enumerate_instance_layer_properties()?
@@ -74,20 +150,27 @@ pub unsafe fn create_instance(
         .map(|l| l.layer_name)
         .collect::<HashSet<_>>();
 
-    if crate::VALIDATION_ENABLED && !available_layers.contains(&crate::VALIDATION_LAYER) {
-        return Err(anyhow!("Validation layer requested but not supported."));
+    let options = data.options.validation.clone();
+    data.validation = options.enabled;
+    if data.validation && !available_layers.contains(&crate::VALIDATION_LAYER) {
+        warn!("Validation layer requested but not supported; continuing without it.");
+        data.validation = false;
     }
 
-    let layers = if crate::VALIDATION_ENABLED {
+    let layers = if data.validation {
         vec![crate::VALIDATION_LAYER.as_ptr()]
     } else {
         Vec::new()
     };
 
-    let mut extensions = vk_window::get_required_instance_extensions(window)
-        .iter()
-        .map(|e| e.as_ptr())
-        .collect::<Vec<_>>();
+    // Headless instances render offscreen and need no surface extensions.
+    let mut extensions = match window {
+        Some(window) => vk_window::get_required_instance_extensions(window)
+            .iter()
+            .map(|e| e.as_ptr())
+            .collect::<Vec<_>>(),
+        None => Vec::new(),
+    };
 
     // Required by Vulkan SDK on macOS since 1.3.216.
     let flags = if cfg!(target_os = "macos") && entry.version()? >= crate::PORTABILITY_MACOS_VERSION
@@ -104,10 +187,39 @@ pub unsafe fn create_instance(
         vk::InstanceCreateFlags::empty()
     };
 
-    if crate::VALIDATION_ENABLED {
+    // Graphics debuggers like RenderDoc provide debug utils for naming objects
+    // even without the validation layer.
+    let debug_utils = data.validation
+        || (cfg!(feature = "debug-utils")
+            && entry
+                .enumerate_instance_extension_properties(None)?
+                .iter()
+                .any(|e| e.extension_name == vk::EXT_DEBUG_UTILS_EXTENSION.name));
+    if debug_utils {
         extensions.push(vk::EXT_DEBUG_UTILS_EXTENSION.name.as_ptr());
     }
 
+    // The validation features extension is provided by the layer itself.
+    let mut validation_features = Vec::new();
+    if data.validation {
+        let features = options.enabled_features();
+        if !features.is_empty() {
+            let layer = Some(crate::VALIDATION_LAYER.as_bytes());
+            let supported = entry
+                .enumerate_instance_extension_properties(layer)?
+                .iter()
+                .any(|e| e.extension_name == validation::VALIDATION_FEATURES_EXTENSION.name);
+
+            if supported {
+                info!("Enabling validation features: {:?}", features);
+                extensions.push(validation::VALIDATION_FEATURES_EXTENSION.name.as_ptr());
+                validation_features = features;
+            } else {
+                warn!("Validation features requested but not supported; ignoring them.");
+            }
+        }
+    }
+
     let mut info = vk::InstanceCreateInfo::builder()
         .application_info(&application_info)
         .enabled_layer_names(&layers)
@@ -115,41 +227,34 @@ pub unsafe fn create_instance(
         .flags(flags);
 
     let mut debug_info = vk::DebugUtilsMessengerCreateInfoEXT::builder()
-        .message_severity(vk::DebugUtilsMessageSeverityFlagsEXT::all())
-        .message_type(vk::DebugUtilsMessageTypeFlagsEXT::all())
-        .user_callback(Some(debug_callback));
+        .message_severity(options.severity)
+        .message_type(options.message_type)
+        .user_callback(Some(validation::debug_callback));
 
-    if crate::VALIDATION_ENABLED {
+    // The messenger keeps its own reference to the collector, since the
+    // instance may outlive the app data.
+    let messages = Arc::new(validation::MessageCollector::new(&options)?);
+    debug_info.user_data = Arc::as_ptr(&messages) as *mut c_void;
+    data.messages = messages.clone();
+
+    let mut features_info =
+        vk::ValidationFeaturesEXT::builder().enabled_validation_features(&validation_features);
+
+    if data.validation {
         info = info.push_next(&mut debug_info);
     }
 
-    let instance = entry.create_instance(&info, None)?;
-
-    if crate::VALIDATION_ENABLED {
-        data.messenger = instance.create_debug_utils_messenger_ext(&debug_info, None)?;
+    if !validation_features.is_empty() {
+        info = info.push_next(&mut features_info);
     }
 
-    Ok(instance)
-}
+    let mut instance = OwnedInstance::new(entry, entry.create_instance(&info, None)?);
+    instance.set_debug_utils(cfg!(feature = "debug-utils") && debug_utils);
 
-pub extern "system" fn debug_callback(
-    severity: vk::DebugUtilsMessageSeverityFlagsEXT,
-    type_: vk::DebugUtilsMessageTypeFlagsEXT,
-    data: *const vk::DebugUtilsMessengerCallbackDataEXT,
-    _: *mut c_void,
-) -> vk::Bool32 {
-    let data = unsafe { *data };
-    let message = unsafe { CStr::from_ptr(data.message) }.to_string_lossy();
-
-    if severity >= vk::DebugUtilsMessageSeverityFlagsEXT::ERROR {
-        error!("({:?}) {}", type_, message);
-    } else if severity >= vk::DebugUtilsMessageSeverityFlagsEXT::WARNING {
-        warn!("({:?}) {}", type_, message);
-    } else if severity >= vk::DebugUtilsMessageSeverityFlagsEXT::INFO {
-        debug!("({:?}) {}", type_, message);
-    } else {
-        trace!("({:?}) {}", type_, message);
+    if data.validation {
+        let messenger = instance.create_debug_utils_messenger_ext(&debug_info, None)?;
+        instance.set_messenger(messenger, messages);
     }
 
-    vk::FALSE
+    Ok(SharedInstance::new(instance))
 }
@@ -0,0 +1,279 @@
+use crate::gfx::resource::{Buffer, SharedDevice};
+use crate::gfx::vertex::*;
+use crate::gfx::*;
+use anyhow::{anyhow, Result};
+use cgmath::{vec2, vec3};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// A material referenced by a loaded mesh.
+#[derive(Clone, Debug, Default)]
+pub struct Material {
+    pub name: String,
+    pub diffuse: Option<[f32; 3]>,
+    pub diffuse_texture: Option<String>,
+}
+
+/// Geometry uploaded to device-local vertex and index buffers.
+#[derive(Debug, Default)]
+pub struct Mesh {
+    pub name: String,
+    pub vertex_buffer: Buffer,
+    pub index_buffer: Buffer,
+    pub index_count: u32,
+    pub index_type: vk::IndexType,
+    /// Index into `AppData::materials`.
+    pub material: Option<usize>,
+}
+
+/// An object to draw in the next frame.
+#[derive(Clone, Debug)]
+pub struct DrawItem {
+    /// Index into `AppData::meshes`.
+    pub mesh: usize,
+    /// Index into `AppData::materials`, overriding the mesh's own material.
+    pub material: Option<usize>,
+    pub transform: Mat4,
+    /// Labels the draw in graphics debuggers and validation messages.
+    pub label: Option<String>,
+}
+
+/// Loads every shape in an `.obj` file (and its `.mtl` materials, if any).
+///
+/// The meshes are returned instead of replacing `data.meshes`, so the current
+/// ones can keep rendering while the new ones upload on the transfer queue.
+pub unsafe fn load_model(
+    instance: &Instance,
+    device: &SharedDevice,
+    data: &mut AppData,
+    path: &Path,
+) -> Result<(Vec<Mesh>, Vec<Material>)> {
+    let options = tobj::LoadOptions {
+        triangulate: true,
+        ..Default::default()
+    };
+
+    let (models, materials) = tobj::load_obj(path, &options)?;
+
+    let materials = match materials {
+        Ok(materials) => materials
+            .into_iter()
+            .map(|m| Material {
+                name: m.name,
+                diffuse: m.diffuse,
+                diffuse_texture: m.diffuse_texture,
+            })
+            .collect(),
+        Err(e) => {
+            warn!("Failed to load materials for `{}`: {}", path.display(), e);
+            Vec::new()
+        }
+    };
+
+    let mut meshes = Vec::with_capacity(models.len());
+    for model in &models {
+        if model.mesh.indices.is_empty() {
+            warn!("Skipping empty shape `{}`.", model.name);
+            continue;
+        }
+
+        let (vertices, indices) = deduplicate_vertices(&model.mesh);
+        info!(
+            "Loaded shape `{}` ({} vertices, {} indices).",
+            model.name,
+            vertices.len(),
+            indices.len()
+        );
+
+        let material = model
+            .mesh
+            .material_id
+            .filter(|m| *m < materials.len());
+        meshes.push(create_mesh(
+            instance, device, data, &model.name, &vertices, &indices, material,
+        )?);
+    }
+
+    if meshes.is_empty() {
+        return Err(anyhow!("No shapes found in `{}`.", path.display()));
+    }
+
+    Ok((meshes, materials))
+}
+
+/// Uploads the built-in quad as the only mesh.
+pub unsafe fn create_default_mesh(
+    instance: &Instance,
+    device: &SharedDevice,
+    data: &mut AppData,
+) -> Result<()> {
+    let mesh = create_mesh(instance, device, data, "quad", &VERTICES, INDICES, None)?;
+    data.meshes = vec![mesh];
+
+    Ok(())
+}
+
+/// Uploads a mesh, storing its indices as `u16` when the vertex count allows.
+pub unsafe fn create_mesh(
+    instance: &Instance,
+    device: &SharedDevice,
+    data: &mut AppData,
+    name: &str,
+    vertices: &[Vertex],
+    indices: &[u32],
+    material: Option<usize>,
+) -> Result<Mesh> {
+    if vertices.is_empty() || indices.is_empty() {
+        return Err(anyhow!("Mesh `{}` has no geometry.", name));
+    }
+
+    let vertex_buffer = create_device_local_buffer(
+        instance,
+        device,
+        data,
+        &format!("{} vertices", name),
+        vertices,
+        vk::BufferUsageFlags::VERTEX_BUFFER,
+    )?;
+
+    let index_type = index_type(vertices.len());
+    let index_buffer = if index_type == vk::IndexType::UINT16 {
+        let indices = indices.iter().map(|i| *i as u16).collect::<Vec<_>>();
+        create_device_local_buffer(
+            instance,
+            device,
+            data,
+            &format!("{} indices", name),
+            &indices,
+            vk::BufferUsageFlags::INDEX_BUFFER,
+        )?
+    } else {
+        create_device_local_buffer(
+            instance,
+            device,
+            data,
+            &format!("{} indices", name),
+            indices,
+            vk::BufferUsageFlags::INDEX_BUFFER,
+        )?
+    };
+
+    Ok(Mesh {
+        name: name.to_string(),
+        vertex_buffer,
+        index_buffer,
+        index_count: indices.len() as u32,
+        index_type,
+        material,
+    })
+}
+
+/// The smallest index type that can address `vertex_count` vertices.
+fn index_type(vertex_count: usize) -> vk::IndexType {
+    if vertex_count <= u16::MAX as usize {
+        vk::IndexType::UINT16
+    } else {
+        vk::IndexType::UINT32
+    }
+}
+
+/// Builds one vertex per unique position/texture coordinate/color combination.
+fn deduplicate_vertices(mesh: &tobj::Mesh) -> (Vec<Vertex>, Vec<u32>) {
+    let mut unique_vertices = HashMap::new();
+    let mut vertices = Vec::new();
+    let mut indices = Vec::with_capacity(mesh.indices.len());
+
+    for (i, index) in mesh.indices.iter().enumerate() {
+        let pos_offset = (3 * index) as usize;
+        let pos = vec3(
+            mesh.positions[pos_offset],
+            mesh.positions[pos_offset + 1],
+            mesh.positions[pos_offset + 2],
+        );
+
+        let color = if mesh.vertex_color.is_empty() {
+            vec3(1.0, 1.0, 1.0)
+        } else {
+            vec3(
+                mesh.vertex_color[pos_offset],
+                mesh.vertex_color[pos_offset + 1],
+                mesh.vertex_color[pos_offset + 2],
+            )
+        };
+
+        // OBJ texture coordinates have their origin at the bottom left.
+        let tex_coord = if mesh.texcoords.is_empty() {
+            vec2(0.0, 0.0)
+        } else {
+            let index = mesh.texcoord_indices.get(i).unwrap_or(index);
+            let tex_coord_offset = (2 * index) as usize;
+            vec2(
+                mesh.texcoords[tex_coord_offset],
+                1.0 - mesh.texcoords[tex_coord_offset + 1],
+            )
+        };
+
+        let vertex = Vertex::new(pos, color, tex_coord);
+        let index = *unique_vertices.entry(vertex).or_insert_with(|| {
+            vertices.push(vertex);
+            vertices.len() as u32 - 1
+        });
+        indices.push(index);
+    }
+
+    (vertices, indices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deduplicate_vertices_remaps_indices() {
+        // Two triangles sharing an edge, with the shared corners repeated.
+        let mesh = tobj::Mesh {
+            positions: vec![
+                0.0, 0.0, 0.0, //
+                1.0, 0.0, 0.0, //
+                1.0, 1.0, 0.0, //
+                1.0, 1.0, 0.0, //
+                0.0, 1.0, 0.0, //
+                0.0, 0.0, 0.0, //
+            ],
+            indices: vec![0, 1, 2, 3, 4, 5],
+            ..Default::default()
+        };
+
+        let (vertices, indices) = deduplicate_vertices(&mesh);
+
+        assert_eq!(vertices.len(), 4);
+        assert_eq!(indices, vec![0, 1, 2, 2, 3, 0]);
+        assert_eq!(vertices[3].pos, vec3(0.0, 1.0, 0.0));
+        assert_eq!(vertices[0].color, vec3(1.0, 1.0, 1.0));
+    }
+
+    #[test]
+    fn deduplicate_vertices_keeps_distinct_tex_coords() {
+        let mesh = tobj::Mesh {
+            positions: vec![0.0, 0.0, 0.0],
+            texcoords: vec![0.0, 0.0, 1.0, 1.0],
+            indices: vec![0, 0, 0],
+            texcoord_indices: vec![0, 1, 0],
+            ..Default::default()
+        };
+
+        let (vertices, indices) = deduplicate_vertices(&mesh);
+
+        assert_eq!(vertices.len(), 2);
+        assert_eq!(indices, vec![0, 1, 0]);
+        // Flipped, since OBJ texture coordinates start at the bottom.
+        assert_eq!(vertices[0].tex_coord, vec2(0.0, 1.0));
+    }
+
+    #[test]
+    fn index_type_fits_vertex_count() {
+        assert_eq!(index_type(4), vk::IndexType::UINT16);
+        assert_eq!(index_type(u16::MAX as usize), vk::IndexType::UINT16);
+        assert_eq!(index_type(u16::MAX as usize + 1), vk::IndexType::UINT32);
+    }
+}
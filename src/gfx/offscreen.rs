@@ -0,0 +1,177 @@
+use crate::gfx::debug::*;
+use crate::gfx::resource::SharedDevice;
+use crate::gfx::texture::create_image;
+use crate::gfx::vertex::*;
+use crate::gfx::*;
+use anyhow::Result;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::ptr::copy_nonoverlapping as memcpy;
+
+/// The format of the offscreen color target, chosen so frames can be written
+/// straight to PNG without swizzling.
+pub const OFFSCREEN_FORMAT: vk::Format = vk::Format::R8G8B8A8_SRGB;
+
+/// A rendered frame read back to CPU memory as tightly packed RGBA8 pixels.
+#[derive(Clone, Debug)]
+pub struct Frame {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+impl Frame {
+    /// Writes the frame to `path` as an 8-bit RGBA PNG.
+    pub fn save_png(&self, path: impl AsRef<Path>) -> Result<()> {
+        let file = File::create(path)?;
+
+        let mut encoder = png::Encoder::new(BufWriter::new(file), self.width, self.height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.pixels)?;
+
+        Ok(())
+    }
+}
+
+/// Creates the color image a headless app renders into in place of the
+/// swapchain images, so the rest of the pipeline can treat it as one.
+pub unsafe fn create_offscreen_images(
+    instance: &Instance,
+    device: &SharedDevice,
+    data: &mut AppData,
+    extent: vk::Extent2D,
+) -> Result<()> {
+    let image = create_image(
+        instance,
+        device,
+        data,
+        "offscreen color",
+        extent.width,
+        extent.height,
+        1,
+        vk::SampleCountFlags::_1,
+        OFFSCREEN_FORMAT,
+        vk::ImageTiling::OPTIMAL,
+        vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_SRC,
+        vk::MemoryPropertyFlags::DEVICE_LOCAL,
+    )?;
+
+    data.swapchain_images = vec![*image];
+    data.offscreen_images = vec![image];
+    data.swapchain_format = OFFSCREEN_FORMAT;
+    data.swapchain_extent = extent;
+
+    Ok(())
+}
+
+/// Copies a finished offscreen image into host memory.
+pub unsafe fn read_offscreen_image(
+    instance: &Instance,
+    device: &SharedDevice,
+    data: &mut AppData,
+    image_index: usize,
+) -> Result<Frame> {
+    let vk::Extent2D { width, height } = data.swapchain_extent;
+    let size = width as u64 * height as u64 * 4;
+
+    let readback_buffer = create_buffer(
+        instance,
+        device,
+        data,
+        "readback",
+        size,
+        vk::BufferUsageFlags::TRANSFER_DST,
+        vk::MemoryPropertyFlags::HOST_COHERENT | vk::MemoryPropertyFlags::HOST_VISIBLE,
+    )?;
+
+    let command_buffer = begin_single_time_commands(device, data)?;
+    let label = begin_label(device, command_buffer, "read back frame", TRANSFER_COLOR);
+
+    let subresource_range = vk::ImageSubresourceRange::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .base_mip_level(0)
+        .level_count(1)
+        .base_array_layer(0)
+        .layer_count(1);
+
+    // The render pass leaves the image in `TRANSFER_SRC_OPTIMAL`; this only
+    // makes the color writes visible to the copy.
+    let barrier = vk::ImageMemoryBarrier::builder()
+        .old_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+        .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .image(data.swapchain_images[image_index])
+        .subresource_range(subresource_range)
+        .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+        .dst_access_mask(vk::AccessFlags::TRANSFER_READ);
+
+    device.cmd_pipeline_barrier(
+        command_buffer,
+        vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+        vk::PipelineStageFlags::TRANSFER,
+        vk::DependencyFlags::empty(),
+        &[] as &[vk::MemoryBarrier],
+        &[] as &[vk::BufferMemoryBarrier],
+        &[barrier],
+    );
+
+    let subresource = vk::ImageSubresourceLayers::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .mip_level(0)
+        .base_array_layer(0)
+        .layer_count(1);
+
+    let region = vk::BufferImageCopy::builder()
+        .buffer_offset(0)
+        .buffer_row_length(0)
+        .buffer_image_height(0)
+        .image_subresource(subresource)
+        .image_offset(vk::Offset3D { x: 0, y: 0, z: 0 })
+        .image_extent(vk::Extent3D {
+            width,
+            height,
+            depth: 1,
+        });
+
+    device.cmd_copy_image_to_buffer(
+        command_buffer,
+        data.swapchain_images[image_index],
+        vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+        *readback_buffer,
+        &[region],
+    );
+
+    let barrier = vk::MemoryBarrier::builder()
+        .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+        .dst_access_mask(vk::AccessFlags::HOST_READ);
+
+    device.cmd_pipeline_barrier(
+        command_buffer,
+        vk::PipelineStageFlags::TRANSFER,
+        vk::PipelineStageFlags::HOST,
+        vk::DependencyFlags::empty(),
+        &[barrier],
+        &[] as &[vk::BufferMemoryBarrier],
+        &[] as &[vk::ImageMemoryBarrier],
+    );
+    drop(label);
+
+    end_single_time_commands(device, data, command_buffer)?;
+
+    let mut pixels = vec![0u8; size as usize];
+
+    let memory = readback_buffer.mapped_ptr()?;
+
+    memcpy(memory.cast(), pixels.as_mut_ptr(), pixels.len());
+
+    Ok(Frame {
+        width,
+        height,
+        pixels,
+    })
+}
@@ -1,32 +1,79 @@
-use std::mem::size_of;
-
+use crate::gfx::debug::*;
 use crate::gfx::device::*;
+use crate::gfx::resource::{Owned, SharedDevice};
+use crate::gfx::texture::get_depth_format;
 use crate::gfx::*;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use cgmath::vec4;
+use std::mem::size_of;
 use vulkanalia::bytecode::Bytecode;
 use vulkanalia::vk;
 
-use self::vertex::Vertex;
+use self::model::DrawItem;
+use self::pipeline_cache::create_cached_graphics_pipeline;
+use self::shader::Shaders;
+use self::vertex::{ObjectConstants, Vertex};
+
+/// The shaders the graphics pipeline is built from, by source file name.
+pub const VERT_SHADER: &str = "shader.vert";
+pub const FRAG_SHADER: &str = "shader.frag";
+
+pub unsafe fn create_pipeline(device: &SharedDevice, data: &mut AppData) -> Result<()> {
+    let push_constant_ranges = data.reflection.push_constant_ranges();
+    let size = data.reflection.push_constants.map(|r| r.size);
+    if size != Some(size_of::<ObjectConstants>() as u32) {
+        return Err(anyhow!(
+            "Push constant block is {:?} bytes in the shaders but {} bytes in `ObjectConstants`.",
+            size,
+            size_of::<ObjectConstants>()
+        ));
+    }
+
+    let set_layouts = &[*data.descriptor_set_layout];
+    let layout_info = vk::PipelineLayoutCreateInfo::builder()
+        .set_layouts(set_layouts)
+        .push_constant_ranges(&push_constant_ranges);
+
+    data.pipeline_layout = Owned::new(device, device.create_pipeline_layout(&layout_info, None)?);
+    set_object_name(device, *data.pipeline_layout, "pipeline layout");
+    data.pipeline = create_graphics_pipeline(device, data)?;
+
+    Ok(())
+}
+
+/// Creates the graphics pipeline from the current shader bytecode in
+/// `data.shaders`, using the existing pipeline layout.
+///
+/// Fails if the shaders no longer match that layout or expect vertex inputs
+/// that [`Vertex`] doesn't provide.
+pub unsafe fn create_graphics_pipeline(
+    device: &SharedDevice,
+    data: &AppData,
+) -> Result<Owned<vk::Pipeline>> {
+    let reflection = data.shaders.reflect(&[VERT_SHADER, FRAG_SHADER])?;
+    if !reflection.layout_matches(&data.reflection) {
+        return Err(anyhow!(
+            "Shader resources no longer match the pipeline layout; restart to apply them."
+        ));
+    }
 
-pub unsafe fn create_pipeline(device: &Device, data: &mut AppData) -> Result<()> {
-    let vert = include_bytes!("../../compiled/shader.vert.spv");
-    let frag = include_bytes!("../../compiled/shader.frag.spv");
+    let attribute_descriptions = Vertex::attribute_descriptions();
+    reflection.check_vertex_input(&attribute_descriptions)?;
 
-    let vert_shader_module = create_shader_module(device, &vert[..])?;
-    let frag_shader_module = create_shader_module(device, &frag[..])?;
+    let vert_shader_module = create_shader_module(device, VERT_SHADER, &data.shaders)?;
+    let frag_shader_module = create_shader_module(device, FRAG_SHADER, &data.shaders)?;
 
     let vert_stage = vk::PipelineShaderStageCreateInfo::builder()
         .stage(vk::ShaderStageFlags::VERTEX)
-        .module(vert_shader_module)
+        .module(*vert_shader_module)
         .name(b"main\0");
 
     let frag_stage = vk::PipelineShaderStageCreateInfo::builder()
         .stage(vk::ShaderStageFlags::FRAGMENT)
-        .module(frag_shader_module)
+        .module(*frag_shader_module)
         .name(b"main\0");
 
     let binding_descriptions = &[Vertex::binding_description()];
-    let attribute_descriptions = Vertex::attribute_descriptions();
     let vertex_input_state = vk::PipelineVertexInputStateCreateInfo::builder()
         .vertex_binding_descriptions(binding_descriptions)
         .vertex_attribute_descriptions(&attribute_descriptions);
@@ -63,8 +110,16 @@ pub unsafe fn create_pipeline(device: &Device, data: &mut AppData) -> Result<()>
         .depth_bias_enable(false);
 
     let multisample_state = vk::PipelineMultisampleStateCreateInfo::builder()
-        .sample_shading_enable(false)
-        .rasterization_samples(vk::SampleCountFlags::_1);
+        .sample_shading_enable(data.options.min_sample_shading.is_some())
+        .min_sample_shading(data.options.min_sample_shading.unwrap_or(0.0))
+        .rasterization_samples(data.msaa_samples);
+
+    let depth_stencil_state = vk::PipelineDepthStencilStateCreateInfo::builder()
+        .depth_test_enable(true)
+        .depth_write_enable(true)
+        .depth_compare_op(vk::CompareOp::LESS)
+        .depth_bounds_test_enable(false)
+        .stencil_test_enable(false);
 
     // Color Blend State
 
@@ -79,11 +134,6 @@ pub unsafe fn create_pipeline(device: &Device, data: &mut AppData) -> Result<()>
         .attachments(attachments)
         .blend_constants([0.0, 0.0, 0.0, 0.0]);
 
-    let set_layouts = &[data.descriptor_set_layout];
-    let layout_info = vk::PipelineLayoutCreateInfo::builder().set_layouts(set_layouts);
-
-    data.pipeline_layout = device.create_pipeline_layout(&layout_info, None)?;
-
     let stages = &[vert_stage, frag_stage];
     let info = vk::GraphicsPipelineCreateInfo::builder()
         .stages(stages)
@@ -92,91 +142,190 @@ pub unsafe fn create_pipeline(device: &Device, data: &mut AppData) -> Result<()>
         .viewport_state(&viewport_state)
         .rasterization_state(&rasterization_state)
         .multisample_state(&multisample_state)
+        .depth_stencil_state(&depth_stencil_state)
         .color_blend_state(&color_blend_state)
-        .layout(data.pipeline_layout)
-        .render_pass(data.render_pass)
+        .layout(*data.pipeline_layout)
+        .render_pass(*data.render_pass)
         .subpass(0)
         .base_pipeline_handle(vk::Pipeline::null()) // Optional.
         .base_pipeline_index(-1); // Optional.
 
-    data.pipeline = device
-        .create_graphics_pipelines(vk::PipelineCache::null(), &[info], None)?
-        .0[0];
+    let pipeline = create_cached_graphics_pipeline(device, data, &info)?;
+    set_object_name(device, pipeline, "graphics pipeline");
 
-    device.destroy_shader_module(vert_shader_module, None);
-    device.destroy_shader_module(frag_shader_module, None);
-
-    Ok(())
+    Ok(Owned::new(device, pipeline))
 }
 
-pub unsafe fn create_shader_module(device: &Device, bytecode: &[u8]) -> Result<vk::ShaderModule> {
-    let bytecode = Bytecode::new(bytecode).unwrap();
+/// Creates a module from the bytecode of the shader named `name`.
+pub unsafe fn create_shader_module(
+    device: &SharedDevice,
+    name: &str,
+    shaders: &Shaders,
+) -> Result<Owned<vk::ShaderModule>> {
+    let bytecode = Bytecode::new(shaders.get(name)?)?;
 
     let info = vk::ShaderModuleCreateInfo::builder()
         .code_size(bytecode.code_size())
         .code(bytecode.code());
 
-    Ok(device.create_shader_module(&info, None)?)
+    let module = Owned::new(device, device.create_shader_module(&info, None)?);
+    set_object_name(device, *module, name);
+
+    Ok(module)
 }
 
 pub unsafe fn create_render_pass(
     instance: &Instance,
-    device: &Device,
+    device: &SharedDevice,
     data: &mut AppData,
 ) -> Result<()> {
+    // Offscreen targets are read back instead of presented.
+    let final_layout = if data.headless {
+        vk::ImageLayout::TRANSFER_SRC_OPTIMAL
+    } else {
+        vk::ImageLayout::PRESENT_SRC_KHR
+    };
+
+    // With multisampling the color attachment is the transient target, which
+    // is resolved into the swapchain image at the end of the subpass.
+    let multisampled = data.msaa_samples != vk::SampleCountFlags::_1;
+
     let color_attachment = vk::AttachmentDescription::builder()
         .format(data.swapchain_format)
-        .samples(vk::SampleCountFlags::_1)
+        .samples(data.msaa_samples)
         .load_op(vk::AttachmentLoadOp::CLEAR)
-        .store_op(vk::AttachmentStoreOp::STORE)
+        .store_op(if multisampled {
+            vk::AttachmentStoreOp::DONT_CARE
+        } else {
+            vk::AttachmentStoreOp::STORE
+        })
         .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
         .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
         .initial_layout(vk::ImageLayout::UNDEFINED)
-        .final_layout(vk::ImageLayout::PRESENT_SRC_KHR);
+        .final_layout(if multisampled {
+            vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL
+        } else {
+            final_layout
+        });
 
     let color_attachment_ref = vk::AttachmentReference::builder()
         .attachment(0)
         .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL);
 
+    let depth_stencil_attachment = vk::AttachmentDescription::builder()
+        .format(get_depth_format(instance, data)?)
+        .samples(data.msaa_samples)
+        .load_op(vk::AttachmentLoadOp::CLEAR)
+        .store_op(vk::AttachmentStoreOp::DONT_CARE)
+        .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+        .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .final_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL);
+
+    let depth_stencil_attachment_ref = vk::AttachmentReference::builder()
+        .attachment(1)
+        .layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL);
+
+    let color_resolve_attachment = vk::AttachmentDescription::builder()
+        .format(data.swapchain_format)
+        .samples(vk::SampleCountFlags::_1)
+        .load_op(vk::AttachmentLoadOp::DONT_CARE)
+        .store_op(vk::AttachmentStoreOp::STORE)
+        .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+        .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .final_layout(final_layout);
+
+    let color_resolve_attachment_ref = vk::AttachmentReference::builder()
+        .attachment(2)
+        .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL);
+
     let color_attachments = &[color_attachment_ref];
-    let subpass = vk::SubpassDescription::builder()
+    let resolve_attachments = &[color_resolve_attachment_ref];
+    let mut subpass = vk::SubpassDescription::builder()
         .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
-        .color_attachments(color_attachments);
+        .color_attachments(color_attachments)
+        .depth_stencil_attachment(&depth_stencil_attachment_ref);
+    if multisampled {
+        subpass = subpass.resolve_attachments(resolve_attachments);
+    }
+
+    // The multisampled color image and the depth image are shared by every
+    // frame in flight, so their clears must wait for the previous frame's
+    // color writes and depth tests.
+    let dependency = vk::SubpassDependency::builder()
+        .src_subpass(vk::SUBPASS_EXTERNAL)
+        .dst_subpass(0)
+        .src_stage_mask(
+            vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+        )
+        .src_access_mask(
+            vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+                | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+        )
+        .dst_stage_mask(
+            vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS,
+        )
+        .dst_access_mask(
+            vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+                | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+        );
 
-    let attachments = &[color_attachment];
+    let attachments = if multisampled {
+        vec![
+            color_attachment,
+            depth_stencil_attachment,
+            color_resolve_attachment,
+        ]
+    } else {
+        vec![color_attachment, depth_stencil_attachment]
+    };
     let subpasses = &[subpass];
+    let dependencies = &[dependency];
     let info = vk::RenderPassCreateInfo::builder()
-        .attachments(attachments)
-        .subpasses(subpasses);
+        .attachments(&attachments)
+        .subpasses(subpasses)
+        .dependencies(dependencies);
 
-    data.render_pass = device.create_render_pass(&info, None)?;
+    data.render_pass = Owned::new(device, device.create_render_pass(&info, None)?);
+    set_object_name(device, *data.render_pass, "main render pass");
 
     Ok(())
 }
 
-pub unsafe fn create_framebuffers(device: &Device, data: &mut AppData) -> Result<()> {
+pub unsafe fn create_framebuffers(device: &SharedDevice, data: &mut AppData) -> Result<()> {
     data.framebuffers = data
         .swapchain_image_views
         .iter()
-        .map(|i| {
-            let attachments = &[*i];
+        .enumerate()
+        .map(|(index, i)| {
+            let attachments = if data.msaa_samples == vk::SampleCountFlags::_1 {
+                vec![**i, *data.depth_image_view]
+            } else {
+                vec![*data.color_image_view, *data.depth_image_view, **i]
+            };
             let create_info = vk::FramebufferCreateInfo::builder()
-                .render_pass(data.render_pass)
-                .attachments(attachments)
+                .render_pass(*data.render_pass)
+                .attachments(&attachments)
                 .width(data.swapchain_extent.width)
                 .height(data.swapchain_extent.height)
                 .layers(1);
 
-            device.create_framebuffer(&create_info, None)
+            let framebuffer = Owned::new(device, device.create_framebuffer(&create_info, None)?);
+            set_object_name(device, *framebuffer, &format!("framebuffer {}", index));
+
+            Ok(framebuffer)
         })
-        .collect::<Result<Vec<_>, _>>()?;
+        .collect::<Result<Vec<_>>>()?;
 
     Ok(())
 }
 
 pub unsafe fn create_command_pool(
     instance: &Instance,
-    device: &Device,
+    device: &SharedDevice,
     data: &mut AppData,
 ) -> Result<()> {
     let indices = QueueFamilyIndices::get(instance, data, data.physical_device)?;
@@ -185,85 +334,175 @@ pub unsafe fn create_command_pool(
         .flags(vk::CommandPoolCreateFlags::empty()) // Optional.
         .queue_family_index(indices.graphics);
 
-    data.command_pool = device.create_command_pool(&info, None)?;
+    data.command_pool = Owned::new(device, device.create_command_pool(&info, None)?);
+    set_object_name(device, *data.command_pool, "graphics command pool");
 
     let info = vk::CommandPoolCreateInfo::builder()
         .flags(vk::CommandPoolCreateFlags::empty())
         .queue_family_index(indices.transfer);
 
-    data.transfer_command_pool = device.create_command_pool(&info, None)?;
+    data.transfer_command_pool = Owned::new(device, device.create_command_pool(&info, None)?);
+    set_object_name(device, *data.transfer_command_pool, "transfer command pool");
+
+    // Each frame in flight records into its own pool, which is reset once the
+    // frame's fence shows the GPU is done with it.
+    let info = vk::CommandPoolCreateInfo::builder()
+        .flags(vk::CommandPoolCreateFlags::TRANSIENT)
+        .queue_family_index(indices.graphics);
+
+    data.frame_command_pools = (0..crate::MAX_FRAMES_IN_FLIGHT)
+        .map(|i| {
+            let pool = Owned::new(device, device.create_command_pool(&info, None)?);
+            set_object_name(device, *pool, &format!("frame {} command pool", i));
+            Ok(pool)
+        })
+        .collect::<Result<Vec<_>>>()?;
 
     Ok(())
 }
 
-pub unsafe fn create_command_buffers(device: &Device, data: &mut AppData) -> Result<()> {
-    let allocate_info = vk::CommandBufferAllocateInfo::builder()
-        .command_pool(data.command_pool)
-        .level(vk::CommandBufferLevel::PRIMARY)
-        .command_buffer_count(data.framebuffers.len() as u32);
+/// Allocates a command buffer for each frame in flight from its own pool.
+pub unsafe fn create_command_buffers(device: &SharedDevice, data: &mut AppData) -> Result<()> {
+    data.command_buffers = data
+        .frame_command_pools
+        .iter()
+        .enumerate()
+        .map(|(i, pool)| {
+            let allocate_info = vk::CommandBufferAllocateInfo::builder()
+                .command_pool(**pool)
+                .level(vk::CommandBufferLevel::PRIMARY)
+                .command_buffer_count(1);
+
+            let command_buffer = device.allocate_command_buffers(&allocate_info)?[0];
+            set_object_name(device, command_buffer, &format!("frame {} commands", i));
+            Ok(command_buffer)
+        })
+        .collect::<Result<Vec<_>>>()?;
 
-    data.command_buffers = device.allocate_command_buffers(&allocate_info)?;
+    Ok(())
+}
 
-    for (i, command_buffer) in data.command_buffers.iter().enumerate() {
-        let info = vk::CommandBufferBeginInfo::builder();
+/// Records `draw_list` into the framebuffer of the swapchain image at
+/// `image_index`, using the camera of frame in flight `frame`.
+pub unsafe fn record_command_buffer(
+    device: &SharedDevice,
+    data: &AppData,
+    command_buffer: vk::CommandBuffer,
+    image_index: usize,
+    frame: usize,
+    draw_list: &[DrawItem],
+) -> Result<()> {
+    let info = vk::CommandBufferBeginInfo::builder()
+        .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
 
-        device.begin_command_buffer(*command_buffer, &info)?;
+    device.begin_command_buffer(command_buffer, &info)?;
 
-        let render_area = vk::Rect2D::builder()
-            .offset(vk::Offset2D::default())
-            .extent(data.swapchain_extent);
+    let render_area = vk::Rect2D::builder()
+        .offset(vk::Offset2D::default())
+        .extent(data.swapchain_extent);
 
-        let color_clear_value = vk::ClearValue {
-            color: vk::ClearColorValue {
-                float32: [0.0, 0.0, 0.0, 1.0],
-            },
+    let color_clear_value = vk::ClearValue {
+        color: vk::ClearColorValue {
+            float32: [0.0, 0.0, 0.0, 1.0],
+        },
+    };
+
+    let depth_clear_value = vk::ClearValue {
+        depth_stencil: vk::ClearDepthStencilValue {
+            depth: 1.0,
+            stencil: 0,
+        },
+    };
+
+    let clear_values = &[color_clear_value, depth_clear_value];
+    let info = vk::RenderPassBeginInfo::builder()
+        .render_pass(*data.render_pass)
+        .framebuffer(*data.framebuffers[image_index])
+        .render_area(render_area)
+        .clear_values(clear_values);
+
+    let label = begin_label(device, command_buffer, "main pass", RENDER_PASS_COLOR);
+    device.cmd_begin_render_pass(command_buffer, &info, vk::SubpassContents::INLINE);
+    device.cmd_bind_pipeline(
+        command_buffer,
+        vk::PipelineBindPoint::GRAPHICS,
+        *data.pipeline,
+    );
+    device.cmd_bind_descriptor_sets(
+        command_buffer,
+        vk::PipelineBindPoint::GRAPHICS,
+        *data.pipeline_layout,
+        0,
+        &[data.descriptor_sets[frame]],
+        &[],
+    );
+
+    // The stages must match the reflected range in the pipeline layout.
+    let push_constant_stages = data
+        .reflection
+        .push_constants
+        .map_or(vk::ShaderStageFlags::empty(), |r| r.stage_flags);
+
+    let mut bound_mesh = None;
+    for item in draw_list {
+        let _label = item
+            .label
+            .as_deref()
+            .map(|name| begin_label(device, command_buffer, name, DRAW_COLOR));
+
+        let mesh = &data.meshes[item.mesh];
+        if bound_mesh != Some(item.mesh) {
+            device.cmd_bind_vertex_buffers(command_buffer, 0, &[*mesh.vertex_buffer], &[0]);
+            device.cmd_bind_index_buffer(command_buffer, *mesh.index_buffer, 0, mesh.index_type);
+            bound_mesh = Some(item.mesh);
+        }
+
+        let tint = item
+            .material
+            .or(mesh.material)
+            .and_then(|m| data.materials[m].diffuse)
+            .unwrap_or([1.0; 3]);
+        let constants = ObjectConstants {
+            model: item.transform,
+            tint: vec4(tint[0], tint[1], tint[2], 1.0),
         };
 
-        let clear_values = &[color_clear_value];
-        let info = vk::RenderPassBeginInfo::builder()
-            .render_pass(data.render_pass)
-            .framebuffer(data.framebuffers[i])
-            .render_area(render_area)
-            .clear_values(clear_values);
-
-        device.cmd_begin_render_pass(*command_buffer, &info, vk::SubpassContents::INLINE);
-        device.cmd_bind_pipeline(
-            *command_buffer,
-            vk::PipelineBindPoint::GRAPHICS,
-            data.pipeline,
-        );
-        device.cmd_bind_vertex_buffers(*command_buffer, 0, &[data.vertex_buffer], &[0]);
-        device.cmd_bind_index_buffer(*command_buffer, data.index_buffer, 0, vk::IndexType::UINT16);
-
-        device.cmd_bind_descriptor_sets(
-            *command_buffer,
-            vk::PipelineBindPoint::GRAPHICS,
-            data.pipeline_layout,
+        device.cmd_push_constants(
+            command_buffer,
+            *data.pipeline_layout,
+            push_constant_stages,
             0,
-            &[data.descriptor_sets[i]],
-            &[],
+            std::slice::from_raw_parts(
+                &constants as *const ObjectConstants as *const u8,
+                size_of::<ObjectConstants>(),
+            ),
         );
-        device.cmd_draw_indexed(*command_buffer, vertex::INDICES.len() as u32, 1, 0, 0, 0);
-        device.cmd_end_render_pass(*command_buffer);
-
-        device.end_command_buffer(*command_buffer)?;
+        device.cmd_draw_indexed(command_buffer, mesh.index_count, 1, 0, 0, 0);
     }
+    device.cmd_end_render_pass(command_buffer);
+    drop(label);
+
+    device.end_command_buffer(command_buffer)?;
 
     Ok(())
 }
 
-pub unsafe fn create_sync_objects(device: &Device, data: &mut AppData) -> Result<()> {
+pub unsafe fn create_sync_objects(device: &SharedDevice, data: &mut AppData) -> Result<()> {
     let semaphore_info = vk::SemaphoreCreateInfo::builder();
     let fence_info = vk::FenceCreateInfo::builder().flags(vk::FenceCreateFlags::SIGNALED);
 
-    for _ in 0..crate::MAX_FRAMES_IN_FLIGHT {
-        data.image_available_semaphores
-            .push(device.create_semaphore(&semaphore_info, None)?);
-        data.render_finished_semaphores
-            .push(device.create_semaphore(&semaphore_info, None)?);
+    for i in 0..crate::MAX_FRAMES_IN_FLIGHT {
+        let image_available = Owned::new(device, device.create_semaphore(&semaphore_info, None)?);
+        set_object_name(device, *image_available, &format!("frame {} image available", i));
+        data.image_available_semaphores.push(image_available);
+
+        let render_finished = Owned::new(device, device.create_semaphore(&semaphore_info, None)?);
+        set_object_name(device, *render_finished, &format!("frame {} render finished", i));
+        data.render_finished_semaphores.push(render_finished);
 
-        data.in_flight_fences
-            .push(device.create_fence(&fence_info, None)?);
+        let in_flight = Owned::new(device, device.create_fence(&fence_info, None)?);
+        set_object_name(device, *in_flight, &format!("frame {} in flight", i));
+        data.in_flight_fences.push(in_flight);
     }
 
     data.images_in_flight = data
@@ -0,0 +1,193 @@
+use crate::gfx::debug::set_object_name;
+use crate::gfx::resource::{Owned, SharedDevice};
+use crate::gfx::*;
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// The size of a `VK_PIPELINE_CACHE_HEADER_VERSION_ONE` header.
+const HEADER_SIZE: usize = 16 + vk::UUID_SIZE;
+
+/// The per-user cache file: under `$XDG_CACHE_HOME` or `~/.cache` on Linux,
+/// `~/Library/Caches` on macOS and `%LOCALAPPDATA%` on Windows.
+pub fn default_pipeline_cache_path() -> Option<PathBuf> {
+    let var = |name| std::env::var_os(name).filter(|v| !v.is_empty()).map(PathBuf::from);
+
+    let dir = if cfg!(windows) {
+        var("LOCALAPPDATA")
+    } else if cfg!(target_os = "macos") {
+        var("HOME").map(|home| home.join("Library/Caches"))
+    } else {
+        var("XDG_CACHE_HOME").or_else(|| var("HOME").map(|home| home.join(".cache")))
+    };
+
+    dir.map(|dir| dir.join("vk-test").join("pipeline_cache.bin"))
+}
+
+/// Creates the pipeline cache shared by all pipeline creation, seeded from
+/// the cache file if it was written by this same device and driver.
+pub unsafe fn create_pipeline_cache(
+    instance: &Instance,
+    device: &SharedDevice,
+    data: &mut AppData,
+) -> Result<()> {
+    let properties = instance.get_physical_device_properties(data.physical_device);
+
+    let initial_data = match &data.options.pipeline_cache_path {
+        Some(path) => read_pipeline_cache(path, &properties),
+        None => Vec::new(),
+    };
+
+    let info = vk::PipelineCacheCreateInfo::builder().initial_data(&initial_data);
+    let cache = match device.create_pipeline_cache(&info, None) {
+        Ok(cache) => cache,
+        // Drivers may still reject data that passed the header checks.
+        Err(e) if !initial_data.is_empty() => {
+            warn!("Discarding pipeline cache rejected by the driver ({}).", e);
+            let info = vk::PipelineCacheCreateInfo::builder();
+            device.create_pipeline_cache(&info, None)?
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    data.pipeline_cache = Owned::new(device, cache);
+    set_object_name(device, cache, "pipeline cache");
+
+    Ok(())
+}
+
+/// Reads the cache file, returning no data if it is missing, truncated or was
+/// written by a different device or driver.
+fn read_pipeline_cache(path: &Path, properties: &vk::PhysicalDeviceProperties) -> Vec<u8> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            debug!(
+                "No pipeline cache loaded from `{}` ({}).",
+                path.display(),
+                e
+            );
+            return Vec::new();
+        }
+    };
+
+    match check_header(&bytes, properties) {
+        Ok(()) => {
+            info!(
+                "Loaded {} byte pipeline cache from `{}`.",
+                bytes.len(),
+                path.display()
+            );
+            bytes
+        }
+        Err(reason) => {
+            warn!(
+                "Discarding pipeline cache `{}`: {}.",
+                path.display(),
+                reason
+            );
+            Vec::new()
+        }
+    }
+}
+
+fn check_header(bytes: &[u8], properties: &vk::PhysicalDeviceProperties) -> Result<(), String> {
+    if bytes.len() < HEADER_SIZE {
+        return Err("truncated header".into());
+    }
+
+    let word = |i: usize| u32::from_le_bytes(bytes[i * 4..i * 4 + 4].try_into().unwrap());
+
+    if (word(0) as usize) < HEADER_SIZE || word(0) as usize > bytes.len() {
+        return Err(format!("invalid header size {}", word(0)));
+    }
+
+    if word(1) != vk::PipelineCacheHeaderVersion::ONE.as_raw() as u32 {
+        return Err(format!("unsupported header version {}", word(1)));
+    }
+
+    if word(2) != properties.vendor_id || word(3) != properties.device_id {
+        return Err(format!(
+            "written for vendor {:#x} device {:#x}",
+            word(2),
+            word(3)
+        ));
+    }
+
+    if bytes[16..HEADER_SIZE] != properties.pipeline_cache_uuid[..] {
+        return Err("written by a different driver".into());
+    }
+
+    Ok(())
+}
+
+/// Writes the pipeline cache to the cache file, replacing it atomically.
+pub unsafe fn save_pipeline_cache(device: &SharedDevice, data: &AppData) -> Result<()> {
+    let Some(path) = &data.options.pipeline_cache_path else {
+        return Ok(());
+    };
+
+    let bytes = device.get_pipeline_cache_data(*data.pipeline_cache)?;
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let temp = path.with_extension("tmp");
+    std::fs::write(&temp, &bytes)?;
+    std::fs::rename(&temp, path)?;
+
+    info!(
+        "Saved {} byte pipeline cache to `{}`.",
+        bytes.len(),
+        path.display()
+    );
+
+    Ok(())
+}
+
+/// Creates a graphics pipeline through the shared pipeline cache.
+///
+/// A pipeline that adds data to the cache is logged as a miss, and one that
+/// doesn't as a hit.
+pub unsafe fn create_cached_graphics_pipeline(
+    device: &SharedDevice,
+    data: &AppData,
+    info: &vk::GraphicsPipelineCreateInfo,
+) -> Result<vk::Pipeline> {
+    let cache = *data.pipeline_cache;
+    let before = pipeline_cache_size(device, cache)?;
+    let start = Instant::now();
+
+    let pipeline = device.create_graphics_pipelines(cache, &[*info], None)?.0[0];
+
+    let elapsed = start.elapsed();
+    let after = pipeline_cache_size(device, cache)?;
+    if after > before {
+        info!(
+            "Pipeline cache miss ({:?}, {} bytes added).",
+            elapsed,
+            after - before
+        );
+    } else {
+        info!("Pipeline cache hit ({:?}).", elapsed);
+    }
+
+    Ok(pipeline)
+}
+
+/// The size of the pipeline cache data, queried without copying the data.
+unsafe fn pipeline_cache_size(device: &Device, cache: vk::PipelineCache) -> Result<usize> {
+    let mut size = 0;
+    let result = (device.commands().get_pipeline_cache_data)(
+        device.handle(),
+        cache,
+        &mut size,
+        std::ptr::null_mut(),
+    );
+
+    if result == vk::Result::SUCCESS {
+        Ok(size)
+    } else {
+        Err(vk::ErrorCode::from(result).into())
+    }
+}
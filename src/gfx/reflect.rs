@@ -0,0 +1,454 @@
+//! Descriptor, push constant and vertex input layouts reflected from SPIR-V.
+
+use anyhow::{anyhow, Result};
+use naga::valid::{Capabilities, ValidationFlags, Validator};
+use naga::{
+    AddressSpace, ArraySize, Binding, ImageClass, Module, ScalarKind, ShaderStage, TypeInner,
+    VectorSize,
+};
+use std::collections::BTreeMap;
+use vulkanalia::prelude::v1_0::*;
+
+/// A resource a shader reads through a descriptor set.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DescriptorBinding {
+    pub set: u32,
+    pub binding: u32,
+    pub descriptor_type: vk::DescriptorType,
+    pub count: u32,
+    pub stage_flags: vk::ShaderStageFlags,
+    /// The size of a uniform or storage buffer block, in bytes.
+    pub size: u32,
+}
+
+/// A vertex attribute consumed by a vertex shader.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct VertexInput {
+    pub location: u32,
+    pub format: vk::Format,
+}
+
+/// The interface of a single shader stage.
+#[derive(Clone, Debug, Default)]
+pub struct ShaderReflection {
+    pub stage: vk::ShaderStageFlags,
+    pub bindings: Vec<DescriptorBinding>,
+    /// The size of the push constant block, or 0 if there is none.
+    pub push_constant_size: u32,
+    pub vertex_inputs: Vec<VertexInput>,
+}
+
+impl ShaderReflection {
+    /// Reflects the `main` entry point of a SPIR-V module.
+    pub fn new(bytecode: &[u8]) -> Result<Self> {
+        let module = naga::front::spv::parse_u8_slice(bytecode, &Default::default())?;
+        let (index, entry_point) = module
+            .entry_points
+            .iter()
+            .enumerate()
+            .find(|(_, e)| e.name == "main")
+            .ok_or_else(|| anyhow!("Shader has no `main` entry point."))?;
+
+        // Only the analysis of which globals the entry point uses (including
+        // through the functions it calls) is needed, not full validation.
+        let info = Validator::new(ValidationFlags::empty(), Capabilities::all())
+            .validate(&module)
+            .map_err(|e| anyhow!("Failed to analyze shader: {}", e.into_inner()))?;
+        let uses = info.get_entry_point(index);
+
+        let stage = match entry_point.stage {
+            ShaderStage::Vertex => vk::ShaderStageFlags::VERTEX,
+            ShaderStage::Fragment => vk::ShaderStageFlags::FRAGMENT,
+            ShaderStage::Compute => vk::ShaderStageFlags::COMPUTE,
+        };
+
+        let mut reflection = Self {
+            stage,
+            ..Default::default()
+        };
+
+        // Combined image samplers show up as an image and a sampler sharing a
+        // binding, so handles are gathered per binding before being typed.
+        let mut handles = BTreeMap::<(u32, u32), (u32, Option<ImageClass>, bool)>::new();
+
+        for (handle, variable) in module.global_variables.iter() {
+            if uses[handle].is_empty() {
+                continue;
+            }
+
+            let (inner, count) = unwrap_array(&module, &module.types[variable.ty].inner);
+            let size = module.types[variable.ty].inner.size(module.to_ctx());
+
+            let (set, binding) = match (&variable.space, &variable.binding) {
+                (AddressSpace::PushConstant, _) => {
+                    reflection.push_constant_size = size;
+                    continue;
+                }
+                (_, Some(b)) => (b.group, b.binding),
+                (_, None) => continue,
+            };
+
+            let descriptor_type = match variable.space {
+                AddressSpace::Uniform => vk::DescriptorType::UNIFORM_BUFFER,
+                AddressSpace::Storage { .. } => vk::DescriptorType::STORAGE_BUFFER,
+                AddressSpace::Handle => {
+                    let handle = handles
+                        .entry((set, binding))
+                        .or_insert((count, None, false));
+                    match inner {
+                        TypeInner::Image { class, .. } => handle.1 = Some(*class),
+                        TypeInner::Sampler { .. } => handle.2 = true,
+                        _ => return Err(anyhow!("Unsupported handle at binding {}.", binding)),
+                    }
+                    continue;
+                }
+                _ => continue,
+            };
+
+            reflection.bindings.push(DescriptorBinding {
+                set,
+                binding,
+                descriptor_type,
+                count,
+                stage_flags: stage,
+                size,
+            });
+        }
+
+        for ((set, binding), (count, image, sampler)) in handles {
+            let descriptor_type = match (image, sampler) {
+                (Some(ImageClass::Storage { .. }), _) => vk::DescriptorType::STORAGE_IMAGE,
+                (Some(_), true) => vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                (Some(_), false) => vk::DescriptorType::SAMPLED_IMAGE,
+                (None, _) => vk::DescriptorType::SAMPLER,
+            };
+
+            reflection.bindings.push(DescriptorBinding {
+                set,
+                binding,
+                descriptor_type,
+                count,
+                stage_flags: stage,
+                size: 0,
+            });
+        }
+
+        if stage == vk::ShaderStageFlags::VERTEX {
+            for argument in &entry_point.function.arguments {
+                if let Some(Binding::Location { location, .. }) = argument.binding {
+                    let inner = &module.types[argument.ty].inner;
+                    let format = vertex_format(inner).ok_or_else(|| {
+                        anyhow!("Unsupported vertex input type at location {}.", location)
+                    })?;
+                    reflection
+                        .vertex_inputs
+                        .push(VertexInput { location, format });
+                }
+            }
+        }
+
+        Ok(reflection)
+    }
+}
+
+/// The layout of a pipeline, merged from all of its stages.
+#[derive(Clone, Debug, Default)]
+pub struct PipelineReflection {
+    /// Sorted by set, then binding.
+    pub bindings: Vec<DescriptorBinding>,
+    pub push_constants: Option<vk::PushConstantRange>,
+    pub vertex_inputs: Vec<VertexInput>,
+}
+
+impl PipelineReflection {
+    pub fn new(stages: &[ShaderReflection]) -> Result<Self> {
+        let mut bindings = BTreeMap::<(u32, u32), DescriptorBinding>::new();
+        let mut push_constants: Option<vk::PushConstantRange> = None;
+        let mut vertex_inputs = Vec::new();
+
+        for stage in stages {
+            for binding in &stage.bindings {
+                let key = (binding.set, binding.binding);
+                match bindings.get_mut(&key) {
+                    Some(existing) => {
+                        if existing.descriptor_type != binding.descriptor_type
+                            || existing.count != binding.count
+                        {
+                            return Err(anyhow!(
+                                "Shader stages disagree on set {} binding {}.",
+                                binding.set,
+                                binding.binding
+                            ));
+                        }
+                        existing.stage_flags |= binding.stage_flags;
+                        existing.size = existing.size.max(binding.size);
+                    }
+                    None => {
+                        bindings.insert(key, *binding);
+                    }
+                }
+            }
+
+            if stage.push_constant_size > 0 {
+                let range = push_constants.get_or_insert_with(Default::default);
+                range.stage_flags |= stage.stage;
+                range.size = range.size.max(stage.push_constant_size);
+            }
+
+            vertex_inputs.extend_from_slice(&stage.vertex_inputs);
+        }
+
+        Ok(Self {
+            bindings: bindings.into_values().collect(),
+            push_constants,
+            vertex_inputs,
+        })
+    }
+
+    /// Returns the binding at `set` and `binding`, if any stage uses it.
+    pub fn binding(&self, set: u32, binding: u32) -> Option<&DescriptorBinding> {
+        self.bindings
+            .iter()
+            .find(|b| b.set == set && b.binding == binding)
+    }
+
+    pub fn set_layout_bindings(&self, set: u32) -> Vec<vk::DescriptorSetLayoutBinding> {
+        self.bindings
+            .iter()
+            .filter(|b| b.set == set)
+            .map(|b| {
+                vk::DescriptorSetLayoutBinding::builder()
+                    .binding(b.binding)
+                    .descriptor_type(b.descriptor_type)
+                    .descriptor_count(b.count)
+                    .stage_flags(b.stage_flags)
+                    .build()
+            })
+            .collect()
+    }
+
+    /// Returns the pool sizes needed to allocate `sets` copies of every set.
+    pub fn pool_sizes(&self, sets: u32) -> Vec<vk::DescriptorPoolSize> {
+        let mut counts = BTreeMap::<i32, u32>::new();
+        for binding in &self.bindings {
+            *counts.entry(binding.descriptor_type.as_raw()).or_default() += binding.count;
+        }
+
+        counts
+            .into_iter()
+            .map(|(type_, count)| {
+                vk::DescriptorPoolSize::builder()
+                    .type_(vk::DescriptorType::from_raw(type_))
+                    .descriptor_count(count * sets)
+                    .build()
+            })
+            .collect()
+    }
+
+    pub fn push_constant_ranges(&self) -> Vec<vk::PushConstantRange> {
+        self.push_constants.into_iter().collect()
+    }
+
+    /// Whether a pipeline with these shaders can use a layout created from
+    /// `other`.
+    pub fn layout_matches(&self, other: &Self) -> bool {
+        self.bindings == other.bindings && self.push_constants == other.push_constants
+    }
+
+    /// Checks that `attributes` provide every vertex shader input in the
+    /// expected format.
+    pub fn check_vertex_input(
+        &self,
+        attributes: &[vk::VertexInputAttributeDescription],
+    ) -> Result<()> {
+        for input in &self.vertex_inputs {
+            let attribute = attributes
+                .iter()
+                .find(|a| a.location == input.location)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Vertex shader input at location {} has no matching vertex attribute.",
+                        input.location
+                    )
+                })?;
+
+            if attribute.format != input.format {
+                return Err(anyhow!(
+                    "Vertex shader input at location {} expects {:?}, but the vertex attribute is {:?}.",
+                    input.location,
+                    input.format,
+                    attribute.format
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the element type and count of a (binding) array, or the type itself
+/// with a count of 1.
+fn unwrap_array<'a>(module: &'a Module, inner: &'a TypeInner) -> (&'a TypeInner, u32) {
+    match inner {
+        TypeInner::BindingArray {
+            base,
+            size: ArraySize::Constant(size),
+        } => (&module.types[*base].inner, size.get()),
+        _ => (inner, 1),
+    }
+}
+
+fn vertex_format(inner: &TypeInner) -> Option<vk::Format> {
+    let (size, scalar) = match inner {
+        TypeInner::Scalar(scalar) => (None, scalar),
+        TypeInner::Vector { size, scalar } => (Some(*size), scalar),
+        _ => return None,
+    };
+
+    if scalar.width != 4 {
+        return None;
+    }
+
+    use vk::Format as F;
+    let formats = match scalar.kind {
+        ScalarKind::Float => [
+            F::R32_SFLOAT,
+            F::R32G32_SFLOAT,
+            F::R32G32B32_SFLOAT,
+            F::R32G32B32A32_SFLOAT,
+        ],
+        ScalarKind::Sint => [
+            F::R32_SINT,
+            F::R32G32_SINT,
+            F::R32G32B32_SINT,
+            F::R32G32B32A32_SINT,
+        ],
+        ScalarKind::Uint => [
+            F::R32_UINT,
+            F::R32G32_UINT,
+            F::R32G32B32_UINT,
+            F::R32G32B32A32_UINT,
+        ],
+        _ => return None,
+    };
+
+    Some(match size {
+        None => formats[0],
+        Some(VectorSize::Bi) => formats[1],
+        Some(VectorSize::Tri) => formats[2],
+        Some(VectorSize::Quad) => formats[3],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gfx::glsl::compile_glsl_source;
+    use std::path::Path;
+
+    const VERTEX: &str = "#version 450
+        layout(push_constant) uniform Constants { mat4 model; } constants;
+        layout(location = 0) in vec3 inPosition;
+        layout(location = 1) in vec2 inTexCoord;
+        layout(location = 0) out vec2 fragTexCoord;
+        void main() {
+            gl_Position = constants.model * vec4(inPosition, 1.0);
+            fragTexCoord = inTexCoord;
+        }";
+
+    fn reflect(stage: naga::ShaderStage, source: &str) -> ShaderReflection {
+        let bytecode = compile_glsl_source(source, stage, Path::new("test")).unwrap();
+        ShaderReflection::new(&bytecode).unwrap()
+    }
+
+    fn fragment(declarations: &str, color: &str) -> ShaderReflection {
+        let source = format!(
+            "#version 450
+            {}
+            layout(location = 0) in vec2 fragTexCoord;
+            layout(location = 0) out vec4 outColor;
+            void main() {{ outColor = {}; }}",
+            declarations, color
+        );
+        reflect(naga::ShaderStage::Fragment, &source)
+    }
+
+    fn attribute(location: u32, format: vk::Format) -> vk::VertexInputAttributeDescription {
+        vk::VertexInputAttributeDescription::builder()
+            .location(location)
+            .format(format)
+            .build()
+    }
+
+    #[test]
+    fn image_and_sampler_on_one_binding_merge() {
+        let reflection = fragment(
+            "layout(binding = 1) uniform texture2D image;
+            layout(binding = 1) uniform sampler imageSampler;",
+            "texture(sampler2D(image, imageSampler), fragTexCoord)",
+        );
+
+        assert_eq!(reflection.bindings.len(), 1);
+        let binding = reflection.bindings[0];
+        assert_eq!((binding.set, binding.binding), (0, 1));
+        assert_eq!(
+            binding.descriptor_type,
+            vk::DescriptorType::COMBINED_IMAGE_SAMPLER
+        );
+        assert_eq!(binding.stage_flags, vk::ShaderStageFlags::FRAGMENT);
+    }
+
+    #[test]
+    fn unused_globals_are_skipped() {
+        let reflection = fragment(
+            "layout(binding = 0) uniform Used { vec4 color; } used;
+            layout(binding = 2) uniform Unused { vec4 color; } unused;",
+            "used.color",
+        );
+
+        assert_eq!(reflection.bindings.len(), 1);
+        assert_eq!(reflection.bindings[0].binding, 0);
+        assert_eq!(
+            reflection.bindings[0].descriptor_type,
+            vk::DescriptorType::UNIFORM_BUFFER
+        );
+        assert_eq!(reflection.bindings[0].size, 16);
+    }
+
+    #[test]
+    fn layout_matches_compares_bindings_and_push_constants() {
+        let vertex = reflect(naga::ShaderStage::Vertex, VERTEX);
+        let color = "layout(binding = 0) uniform Color { vec4 color; } tint;";
+        let a = PipelineReflection::new(&[vertex.clone(), fragment(color, "tint.color")]).unwrap();
+        let b = PipelineReflection::new(&[vertex.clone(), fragment(color, "tint.color")]).unwrap();
+        assert!(a.layout_matches(&b));
+
+        let moved = "layout(binding = 3) uniform Color { vec4 color; } tint;";
+        let c = PipelineReflection::new(&[vertex.clone(), fragment(moved, "tint.color")]).unwrap();
+        assert!(!a.layout_matches(&c));
+
+        let without_vertex = PipelineReflection::new(&[fragment(color, "tint.color")]).unwrap();
+        assert!(!a.layout_matches(&without_vertex));
+    }
+
+    #[test]
+    fn check_vertex_input_requires_matching_formats() {
+        let reflection = PipelineReflection::new(&[reflect(naga::ShaderStage::Vertex, VERTEX)])
+            .unwrap();
+
+        let attributes = [
+            attribute(0, vk::Format::R32G32B32_SFLOAT),
+            attribute(1, vk::Format::R32G32_SFLOAT),
+        ];
+        assert!(reflection.check_vertex_input(&attributes).is_ok());
+
+        let wrong_format = [
+            attribute(0, vk::Format::R32G32B32_SFLOAT),
+            attribute(1, vk::Format::R32G32B32_SFLOAT),
+        ];
+        assert!(reflection.check_vertex_input(&wrong_format).is_err());
+
+        let missing = [attribute(0, vk::Format::R32G32B32_SFLOAT)];
+        assert!(reflection.check_vertex_input(&missing).is_err());
+    }
+}
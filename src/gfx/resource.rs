@@ -0,0 +1,367 @@
+use crate::gfx::memory::{Allocation, Allocator, Resource, ResourceKind};
+use crate::gfx::validation::MessageCollector;
+use anyhow::{anyhow, Result};
+use std::cell::RefCell;
+use std::fmt;
+use std::ops::Deref;
+use std::os::raw::c_void;
+use std::rc::Rc;
+use std::sync::Arc;
+use vulkanalia::prelude::v1_0::*;
+use vulkanalia::vk::ExtDebugUtilsExtension;
+use vulkanalia::vk::KhrSurfaceExtension;
+use vulkanalia::vk::KhrSwapchainExtension;
+
+pub type SharedInstance = Rc<OwnedInstance>;
+pub type SharedDevice = Rc<OwnedDevice>;
+pub type SharedAllocator = Rc<RefCell<Allocator>>;
+
+/// A Vulkan instance and its debug messenger, destroyed once the last device
+/// and surface created from it are gone.
+#[derive(Debug)]
+pub struct OwnedInstance {
+    instance: Instance,
+    messenger: vk::DebugUtilsMessengerEXT,
+    /// The user data of the messenger, kept alive until it is destroyed.
+    messages: Option<Arc<MessageCollector>>,
+    /// Whether objects may be named and labeled through debug utils.
+    debug_utils: bool,
+    /// Keeps the Vulkan loader alive for as long as the instance.
+    entry: Entry,
+}
+
+impl OwnedInstance {
+    pub unsafe fn new(entry: &Entry, instance: Instance) -> Self {
+        Self {
+            instance,
+            messenger: vk::DebugUtilsMessengerEXT::null(),
+            messages: None,
+            debug_utils: false,
+            entry: entry.clone(),
+        }
+    }
+
+    /// Hands the debug messenger over to be destroyed with the instance.
+    pub unsafe fn set_messenger(
+        &mut self,
+        messenger: vk::DebugUtilsMessengerEXT,
+        messages: Arc<MessageCollector>,
+    ) {
+        self.messenger = messenger;
+        self.messages = Some(messages);
+    }
+
+    pub fn set_debug_utils(&mut self, enabled: bool) {
+        self.debug_utils = enabled;
+    }
+
+    /// Whether the debug utils extension is enabled for naming and labeling.
+    pub fn debug_utils(&self) -> bool {
+        self.debug_utils
+    }
+}
+
+impl Deref for OwnedInstance {
+    type Target = Instance;
+
+    fn deref(&self) -> &Instance {
+        &self.instance
+    }
+}
+
+impl Drop for OwnedInstance {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.messenger.is_null() {
+                self.instance
+                    .destroy_debug_utils_messenger_ext(self.messenger, None);
+            }
+            self.instance.destroy_instance(None);
+        }
+    }
+}
+
+/// A window surface, destroyed before its instance.
+#[derive(Debug, Default)]
+pub struct Surface {
+    handle: vk::SurfaceKHR,
+    instance: Option<SharedInstance>,
+}
+
+impl Surface {
+    pub unsafe fn new(instance: &SharedInstance, handle: vk::SurfaceKHR) -> Self {
+        Self {
+            handle,
+            instance: Some(instance.clone()),
+        }
+    }
+}
+
+impl Deref for Surface {
+    type Target = vk::SurfaceKHR;
+
+    fn deref(&self) -> &vk::SurfaceKHR {
+        &self.handle
+    }
+}
+
+impl Drop for Surface {
+    fn drop(&mut self) {
+        if let Some(instance) = &self.instance {
+            unsafe { instance.destroy_surface_khr(self.handle, None) };
+        }
+    }
+}
+
+/// A logical device, destroyed once every resource created from it is gone.
+#[derive(Debug)]
+pub struct OwnedDevice {
+    device: Device,
+    instance: SharedInstance,
+}
+
+impl OwnedDevice {
+    pub unsafe fn new(instance: &SharedInstance, device: Device) -> Self {
+        Self {
+            device,
+            instance: instance.clone(),
+        }
+    }
+
+    /// The instance the device was created from.
+    pub fn instance(&self) -> &SharedInstance {
+        &self.instance
+    }
+}
+
+impl Deref for OwnedDevice {
+    type Target = Device;
+
+    fn deref(&self) -> &Device {
+        &self.device
+    }
+}
+
+impl Drop for OwnedDevice {
+    fn drop(&mut self) {
+        unsafe { self.device.destroy_device(None) };
+    }
+}
+
+/// A device-level handle that can be destroyed on its own.
+pub trait Destroy: vk::Handle {
+    unsafe fn destroy(self, device: &Device);
+}
+
+macro_rules! impl_destroy {
+    ($($handle:ty => $destroy:ident),* $(,)?) => {
+        $(impl Destroy for $handle {
+            unsafe fn destroy(self, device: &Device) {
+                device.$destroy(self, None);
+            }
+        })*
+    };
+}
+
+impl_destroy! {
+    vk::CommandPool => destroy_command_pool,
+    vk::DescriptorPool => destroy_descriptor_pool,
+    vk::DescriptorSetLayout => destroy_descriptor_set_layout,
+    vk::Fence => destroy_fence,
+    vk::Framebuffer => destroy_framebuffer,
+    vk::ImageView => destroy_image_view,
+    vk::Pipeline => destroy_pipeline,
+    vk::PipelineCache => destroy_pipeline_cache,
+    vk::PipelineLayout => destroy_pipeline_layout,
+    vk::RenderPass => destroy_render_pass,
+    vk::Sampler => destroy_sampler,
+    vk::Semaphore => destroy_semaphore,
+    vk::ShaderModule => destroy_shader_module,
+    vk::SwapchainKHR => destroy_swapchain_khr,
+}
+
+/// A device-level handle destroyed when dropped.
+///
+/// The default value owns nothing, which lets `AppData` fill its fields in
+/// as the app is created.
+pub struct Owned<T: Destroy> {
+    handle: T,
+    device: Option<SharedDevice>,
+}
+
+impl<T: Destroy> Owned<T> {
+    /// Takes ownership of `handle`, which must have been created from
+    /// `device` and must not be destroyed elsewhere.
+    pub unsafe fn new(device: &SharedDevice, handle: T) -> Self {
+        Self {
+            handle,
+            device: Some(device.clone()),
+        }
+    }
+}
+
+impl<T: Destroy> Default for Owned<T> {
+    fn default() -> Self {
+        Self {
+            handle: T::default(),
+            device: None,
+        }
+    }
+}
+
+impl<T: Destroy> Deref for Owned<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.handle
+    }
+}
+
+impl<T: Destroy> fmt::Debug for Owned<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.handle.fmt(f)
+    }
+}
+
+impl<T: Destroy> Drop for Owned<T> {
+    fn drop(&mut self) {
+        if let Some(device) = &self.device {
+            unsafe { self.handle.destroy(device) };
+        }
+    }
+}
+
+/// A buffer and the memory bound to it, both released when dropped.
+#[derive(Debug, Default)]
+pub struct Buffer {
+    handle: vk::Buffer,
+    allocation: Allocation,
+    allocator: Option<SharedAllocator>,
+}
+
+impl Buffer {
+    /// Creates a buffer and binds freshly allocated memory to it.
+    pub unsafe fn new(
+        allocator: &SharedAllocator,
+        info: &vk::BufferCreateInfo,
+        properties: vk::MemoryPropertyFlags,
+    ) -> Result<Self> {
+        let device = allocator.borrow().device()?;
+
+        let mut buffer = Self {
+            handle: device.create_buffer(info, None)?,
+            allocation: Allocation::default(),
+            allocator: Some(allocator.clone()),
+        };
+
+        buffer.allocation = allocator.borrow_mut().allocate(
+            Resource::Buffer(buffer.handle),
+            properties,
+            ResourceKind::Linear,
+        )?;
+
+        device.bind_buffer_memory(
+            buffer.handle,
+            buffer.allocation.memory,
+            buffer.allocation.offset,
+        )?;
+
+        Ok(buffer)
+    }
+
+    /// Returns a pointer to the buffer's persistently mapped memory.
+    pub fn mapped_ptr(&self) -> Result<*mut c_void> {
+        match &self.allocator {
+            Some(allocator) => allocator.borrow().mapped_ptr(&self.allocation),
+            None => Err(anyhow!("Buffer has no memory.")),
+        }
+    }
+}
+
+impl Deref for Buffer {
+    type Target = vk::Buffer;
+
+    fn deref(&self) -> &vk::Buffer {
+        &self.handle
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        if let Some(allocator) = &self.allocator {
+            let mut allocator = allocator.borrow_mut();
+            if let Ok(device) = allocator.device() {
+                unsafe {
+                    device.destroy_buffer(self.handle, None);
+                    allocator.free(self.allocation);
+                }
+            }
+        }
+    }
+}
+
+/// An image and the memory bound to it, both released when dropped.
+#[derive(Debug, Default)]
+pub struct Image {
+    handle: vk::Image,
+    allocation: Allocation,
+    allocator: Option<SharedAllocator>,
+}
+
+impl Image {
+    /// Creates an image and binds freshly allocated memory to it.
+    pub unsafe fn new(
+        allocator: &SharedAllocator,
+        info: &vk::ImageCreateInfo,
+        properties: vk::MemoryPropertyFlags,
+    ) -> Result<Self> {
+        let device = allocator.borrow().device()?;
+
+        let mut image = Self {
+            handle: device.create_image(info, None)?,
+            allocation: Allocation::default(),
+            allocator: Some(allocator.clone()),
+        };
+
+        let kind = if info.tiling == vk::ImageTiling::LINEAR {
+            ResourceKind::Linear
+        } else {
+            ResourceKind::Optimal
+        };
+
+        image.allocation =
+            allocator
+                .borrow_mut()
+                .allocate(Resource::Image(image.handle), properties, kind)?;
+
+        device.bind_image_memory(
+            image.handle,
+            image.allocation.memory,
+            image.allocation.offset,
+        )?;
+
+        Ok(image)
+    }
+}
+
+impl Deref for Image {
+    type Target = vk::Image;
+
+    fn deref(&self) -> &vk::Image {
+        &self.handle
+    }
+}
+
+impl Drop for Image {
+    fn drop(&mut self) {
+        if let Some(allocator) = &self.allocator {
+            let mut allocator = allocator.borrow_mut();
+            if let Ok(device) = allocator.device() {
+                unsafe {
+                    device.destroy_image(self.handle, None);
+                    allocator.free(self.allocation);
+                }
+            }
+        }
+    }
+}
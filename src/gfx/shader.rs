@@ -0,0 +1,117 @@
+use crate::gfx::glsl::{compile_glsl, shader_stage};
+use crate::gfx::reflect::{PipelineReflection, ShaderReflection};
+use anyhow::{anyhow, Result};
+use log::*;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver};
+
+/// SPIR-V bytecode for each shader, keyed by its source file name.
+///
+/// Starts out with the shaders compiled into the binary by the build script,
+/// which hot reloading replaces as their sources change.
+#[derive(Clone, Debug)]
+pub struct Shaders {
+    bytecode: HashMap<String, Vec<u8>>,
+}
+
+impl Default for Shaders {
+    fn default() -> Self {
+        let embedded: &[(&str, &[u8])] = include!(concat!(env!("OUT_DIR"), "/shaders.rs"));
+
+        Self {
+            bytecode: embedded
+                .iter()
+                .map(|(name, bytecode)| (name.to_string(), bytecode.to_vec()))
+                .collect(),
+        }
+    }
+}
+
+impl Shaders {
+    pub fn get(&self, name: &str) -> Result<&[u8]> {
+        self.bytecode
+            .get(name)
+            .map(|b| b.as_slice())
+            .ok_or_else(|| anyhow!("No shader named `{}`.", name))
+    }
+
+    /// Reflects the layout of a pipeline built from the named shaders.
+    pub fn reflect(&self, names: &[&str]) -> Result<PipelineReflection> {
+        let stages = names
+            .iter()
+            .map(|name| {
+                ShaderReflection::new(self.get(name)?)
+                    .map_err(|e| anyhow!("Failed to reflect `{}`: {}", name, e))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        PipelineReflection::new(&stages)
+    }
+
+    /// Replaces a shader's bytecode, returning the previous bytecode.
+    pub fn insert(&mut self, name: &str, bytecode: Vec<u8>) -> Option<Vec<u8>> {
+        self.bytecode.insert(name.to_string(), bytecode)
+    }
+}
+
+/// Watches a directory of GLSL shaders and recompiles the ones that change.
+#[derive(Debug)]
+pub struct ShaderWatcher {
+    _watcher: RecommendedWatcher,
+    events: Receiver<notify::Result<notify::Event>>,
+}
+
+impl ShaderWatcher {
+    pub fn new(dir: &Path) -> Result<Self> {
+        let (sender, events) = channel();
+        let mut watcher = notify::recommended_watcher(sender)?;
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+        info!("Watching `{}` for shader changes.", dir.display());
+
+        Ok(Self {
+            _watcher: watcher,
+            events,
+        })
+    }
+
+    /// Returns the shader sources modified since the last call.
+    pub fn changed(&self) -> Vec<PathBuf> {
+        let mut paths = BTreeSet::new();
+        for event in self.events.try_iter() {
+            match event {
+                Ok(event) if event.kind.is_create() || event.kind.is_modify() => {
+                    paths.extend(event.paths.into_iter().filter(|p| shader_stage(p).is_some()));
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Shader watcher error: {}", e),
+            }
+        }
+
+        paths.into_iter().collect()
+    }
+
+    /// Recompiles the shader sources modified since the last call.
+    ///
+    /// Returns the new bytecode keyed by file name. Shaders that fail to
+    /// compile are logged and left out, so the old bytecode stays in use.
+    pub fn recompile(&self) -> Vec<(String, Vec<u8>)> {
+        self.changed()
+            .into_iter()
+            .filter_map(|path| {
+                let name = path.file_name()?.to_str()?.to_string();
+                match compile_glsl(&path) {
+                    Ok(bytecode) => {
+                        info!("Recompiled `{}`.", path.display());
+                        Some((name, bytecode))
+                    }
+                    Err(e) => {
+                        error!("Failed to compile `{}`:\n{}", path.display(), e);
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+}
@@ -1,7 +1,12 @@
+use crate::gfx::debug::*;
 use crate::gfx::device::*;
+use crate::gfx::resource::{Owned, SharedDevice};
+use crate::gfx::texture::create_image_view;
 use crate::gfx::*;
 
-use anyhow::Result;
+use anyhow::{anyhow, Error, Result};
+use std::fmt;
+use std::str::FromStr;
 use vulkanalia::vk;
 use vulkanalia::vk::KhrSurfaceExtension;
 use vulkanalia::vk::KhrSwapchainExtension;
@@ -22,11 +27,11 @@ impl SwapchainSupport {
     ) -> Result<Self> {
         Ok(Self {
             capabilities: instance
-                .get_physical_device_surface_capabilities_khr(physical_device, data.surface)?,
+                .get_physical_device_surface_capabilities_khr(physical_device, *data.surface)?,
             formats: instance
-                .get_physical_device_surface_formats_khr(physical_device, data.surface)?,
+                .get_physical_device_surface_formats_khr(physical_device, *data.surface)?,
             present_modes: instance
-                .get_physical_device_surface_present_modes_khr(physical_device, data.surface)?,
+                .get_physical_device_surface_present_modes_khr(physical_device, *data.surface)?,
         })
     }
 }
@@ -42,11 +47,77 @@ pub fn get_swapchain_surface_format(formats: &[vk::SurfaceFormatKHR]) -> vk::Sur
         .unwrap_or_else(|| formats[0])
 }
 
-pub fn get_swapchain_present_mode(present_modes: &[vk::PresentModeKHR]) -> vk::PresentModeKHR {
-    present_modes
+/// How presented images are paced against the display's refresh.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PresentMode {
+    /// Waits for vertical blank, never tearing. Always supported.
+    Vsync,
+    /// Like `Vsync`, but presents late frames immediately, which may tear.
+    Relaxed,
+    /// Presents without waiting, which may tear.
+    Immediate,
+    /// Waits for vertical blank, replacing queued frames with newer ones.
+    Mailbox,
+}
+
+impl PresentMode {
+    pub const ALL: [Self; 4] = [Self::Vsync, Self::Relaxed, Self::Immediate, Self::Mailbox];
+
+    /// The preferences used when none are given: mailbox, then vsync.
+    pub const DEFAULT_PREFERENCES: &'static [Self] = &[Self::Mailbox, Self::Vsync];
+
+    pub fn to_vk(self) -> vk::PresentModeKHR {
+        match self {
+            Self::Vsync => vk::PresentModeKHR::FIFO,
+            Self::Relaxed => vk::PresentModeKHR::FIFO_RELAXED,
+            Self::Immediate => vk::PresentModeKHR::IMMEDIATE,
+            Self::Mailbox => vk::PresentModeKHR::MAILBOX,
+        }
+    }
+}
+
+impl fmt::Display for PresentMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Self::Vsync => "vsync",
+            Self::Relaxed => "relaxed",
+            Self::Immediate => "immediate",
+            Self::Mailbox => "mailbox",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for PresentMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "vsync" | "fifo" => Ok(Self::Vsync),
+            "relaxed" | "fifo-relaxed" => Ok(Self::Relaxed),
+            "immediate" => Ok(Self::Immediate),
+            "mailbox" => Ok(Self::Mailbox),
+            _ => Err(anyhow!("Unknown present mode `{}`.", s)),
+        }
+    }
+}
+
+/// Picks the first of `preferences` that is supported, falling back to FIFO
+/// (vsync), which every device supports.
+pub fn get_swapchain_present_mode(
+    present_modes: &[vk::PresentModeKHR],
+    preferences: &[PresentMode],
+) -> vk::PresentModeKHR {
+    let preferences = if preferences.is_empty() {
+        PresentMode::DEFAULT_PREFERENCES
+    } else {
+        preferences
+    };
+
+    preferences
         .iter()
-        .cloned()
-        .find(|m| *m == vk::PresentModeKHR::MAILBOX)
+        .map(|m| m.to_vk())
+        .find(|m| present_modes.contains(m))
         .unwrap_or(vk::PresentModeKHR::FIFO)
 }
 
@@ -73,14 +144,18 @@ pub fn get_swapchain_extent(
 pub unsafe fn create_swapchain(
     window: &Window,
     instance: &Instance,
-    device: &Device,
+    device: &SharedDevice,
     data: &mut AppData,
 ) -> Result<()> {
     let indices = QueueFamilyIndices::get(instance, data, data.physical_device)?;
     let support = SwapchainSupport::get(instance, data, data.physical_device)?;
 
     let surface_format = get_swapchain_surface_format(&support.formats);
-    let present_mode = get_swapchain_present_mode(&support.present_modes);
+    let present_mode =
+        get_swapchain_present_mode(&support.present_modes, &data.options.present_modes);
+    if data.swapchain.is_null() || present_mode != data.present_mode {
+        info!("Present mode: {:?}", present_mode);
+    }
     let extent = get_swapchain_extent(window, support.capabilities);
 
     let mut image_count = support.capabilities.min_image_count + 1;
@@ -98,10 +173,9 @@ pub unsafe fn create_swapchain(
     } else {
         vk::SharingMode::EXCLUSIVE
     };
-    queue_family_indices.push(indices.transfer);
 
     let info = vk::SwapchainCreateInfoKHR::builder()
-        .surface(data.surface)
+        .surface(*data.surface)
         .min_image_count(image_count)
         .image_format(surface_format.format)
         .image_color_space(surface_format.color_space)
@@ -114,44 +188,84 @@ pub unsafe fn create_swapchain(
         .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
         .present_mode(present_mode)
         .clipped(true)
-        .old_swapchain(vk::SwapchainKHR::null());
+        .old_swapchain(*data.swapchain);
 
-    data.swapchain = device.create_swapchain_khr(&info, None)?;
-    data.swapchain_images = device.get_swapchain_images_khr(data.swapchain)?;
+    data.swapchain = Owned::new(device, device.create_swapchain_khr(&info, None)?);
+    data.swapchain_images = device.get_swapchain_images_khr(*data.swapchain)?;
+    set_object_name(device, *data.swapchain, "swapchain");
+    for (i, image) in data.swapchain_images.iter().enumerate() {
+        set_object_name(device, *image, &format!("swapchain image {}", i));
+    }
     data.swapchain_format = surface_format.format;
     data.swapchain_extent = extent;
+    data.present_mode = present_mode;
 
     Ok(())
 }
 
-pub unsafe fn create_swapchain_image_views(device: &Device, data: &mut AppData) -> Result<()> {
+pub unsafe fn create_swapchain_image_views(
+    device: &SharedDevice,
+    data: &mut AppData,
+) -> Result<()> {
     data.swapchain_image_views = data
         .swapchain_images
         .iter()
-        .map(|i| {
-            let components = vk::ComponentMapping::builder()
-                .r(vk::ComponentSwizzle::IDENTITY)
-                .g(vk::ComponentSwizzle::IDENTITY)
-                .b(vk::ComponentSwizzle::IDENTITY)
-                .a(vk::ComponentSwizzle::IDENTITY);
-
-            let subresource_range = vk::ImageSubresourceRange::builder()
-                .aspect_mask(vk::ImageAspectFlags::COLOR)
-                .base_mip_level(0)
-                .level_count(1)
-                .base_array_layer(0)
-                .layer_count(1);
-
-            let info = vk::ImageViewCreateInfo::builder()
-                .image(*i)
-                .view_type(vk::ImageViewType::_2D)
-                .format(data.swapchain_format)
-                .components(components)
-                .subresource_range(subresource_range);
-
-            device.create_image_view(&info, None)
+        .enumerate()
+        .map(|(index, i)| {
+            create_image_view(
+                device,
+                &format!("swapchain image {} view", index),
+                *i,
+                data.swapchain_format,
+                vk::ImageAspectFlags::COLOR,
+                1,
+            )
         })
         .collect::<Result<Vec<_>, _>>()?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn present_mode_from_str() {
+        assert_eq!("vsync".parse::<PresentMode>().unwrap(), PresentMode::Vsync);
+        assert_eq!("FIFO".parse::<PresentMode>().unwrap(), PresentMode::Vsync);
+        assert_eq!("fifo-relaxed".parse::<PresentMode>().unwrap(), PresentMode::Relaxed);
+        assert_eq!("Mailbox".parse::<PresentMode>().unwrap(), PresentMode::Mailbox);
+        assert!("".parse::<PresentMode>().is_err());
+        assert!("tearing".parse::<PresentMode>().is_err());
+
+        for mode in PresentMode::ALL {
+            assert_eq!(mode.to_string().parse::<PresentMode>().unwrap(), mode);
+        }
+    }
+
+    #[test]
+    fn present_mode_follows_preferences() {
+        let supported = [vk::PresentModeKHR::FIFO, vk::PresentModeKHR::IMMEDIATE];
+        let preferences = [PresentMode::Mailbox, PresentMode::Immediate];
+
+        assert_eq!(
+            get_swapchain_present_mode(&supported, &preferences),
+            vk::PresentModeKHR::IMMEDIATE
+        );
+    }
+
+    #[test]
+    fn present_mode_falls_back_to_fifo() {
+        let supported = [vk::PresentModeKHR::FIFO];
+
+        assert_eq!(
+            get_swapchain_present_mode(&supported, &[PresentMode::Mailbox]),
+            vk::PresentModeKHR::FIFO
+        );
+        assert_eq!(
+            get_swapchain_present_mode(&supported, &[]),
+            vk::PresentModeKHR::FIFO
+        );
+    }
+}
@@ -1,15 +1,19 @@
+use crate::debug::*;
+use crate::device::QueueFamilyIndices;
+use crate::resource::{Image, Owned, SharedDevice};
 use crate::{vertex::*, AppData};
 use anyhow::{anyhow, Result};
+use log::*;
 use std::fs::File;
 use std::ptr::copy_nonoverlapping as memcpy;
 use vulkanalia::{
-    vk::{self, DeviceV1_0, HasBuilder},
+    vk::{self, DeviceV1_0, HasBuilder, InstanceV1_0},
     Device, Instance,
 };
 
 pub unsafe fn create_texture_image(
     instance: &Instance,
-    device: &Device,
+    device: &SharedDevice,
     data: &mut AppData,
 ) -> Result<()> {
     let image = File::open("resources/texture.png")?;
@@ -20,83 +24,443 @@ pub unsafe fn create_texture_image(
     let mut pixels = vec![0; reader.info().raw_bytes()];
     reader.next_frame(&mut pixels)?;
 
-    let size = reader.info().raw_bytes() as u64;
     let (width, height) = reader.info().size();
-
-    let (staging_buffer, staging_buffer_memory) = create_buffer(
+    let format = vk::Format::R8G8B8A8_SRGB;
+
+    data.texture_mip_levels = (width.max(height) as f32).log2().floor() as u32 + 1;
+
+    // Without linear-filter blits the whole mip chain is built on the CPU.
+    let linear_blit = instance
+        .get_physical_device_format_properties(data.physical_device, format)
+        .optimal_tiling_features
+        .contains(
+            vk::FormatFeatureFlags::BLIT_SRC
+                | vk::FormatFeatureFlags::BLIT_DST
+                | vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR,
+        );
+
+    let mut levels = vec![(pixels, width, height)];
+    if !linear_blit {
+        warn!("Texture format does not support linear blits, generating mipmaps on the CPU.");
+        for _ in 1..data.texture_mip_levels {
+            let (pixels, width, height) = levels.last().unwrap();
+            levels.push(downsample(pixels, *width, *height));
+        }
+    }
+
+    let size = levels.iter().map(|(p, _, _)| p.len()).sum::<usize>() as u64;
+
+    let staging_buffer = create_buffer(
         instance,
         device,
         data,
+        "texture staging",
         size,
         vk::BufferUsageFlags::TRANSFER_SRC,
         vk::MemoryPropertyFlags::HOST_COHERENT | vk::MemoryPropertyFlags::HOST_VISIBLE,
     )?;
 
-    let memory = device.map_memory(staging_buffer_memory, 0, size, vk::MemoryMapFlags::empty())?;
-
-    memcpy(pixels.as_ptr(), memory.cast(), pixels.len());
-
-    device.unmap_memory(staging_buffer_memory);
-
-    let (texture_image, texture_image_memory) = create_image(
+    let memory = staging_buffer.mapped_ptr()?;
+
+    let mut regions = Vec::with_capacity(levels.len());
+    let mut offset = 0;
+    for (pixels, width, height) in &levels {
+        memcpy(
+            pixels.as_ptr(),
+            memory.cast::<u8>().add(offset as usize),
+            pixels.len(),
+        );
+        regions.push((offset, *width, *height));
+        offset += pixels.len() as u64;
+    }
+
+    data.texture_image = create_image(
         instance,
         device,
         data,
+        "texture",
         width,
         height,
-        vk::Format::R8G8B8A8_SRGB,
+        data.texture_mip_levels,
+        vk::SampleCountFlags::_1,
+        format,
         vk::ImageTiling::OPTIMAL,
-        vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST,
+        vk::ImageUsageFlags::SAMPLED
+            | vk::ImageUsageFlags::TRANSFER_DST
+            | vk::ImageUsageFlags::TRANSFER_SRC,
         vk::MemoryPropertyFlags::DEVICE_LOCAL,
     )?;
 
-    data.texture_image = texture_image;
-    data.texture_image_memory = texture_image_memory;
-
-    transition_image_layout(
+    let indices = QueueFamilyIndices::get(instance, data, data.physical_device)?;
+    let image = *data.texture_image;
+    let mip_levels = data.texture_mip_levels;
+
+    // The upload happens on the transfer queue, which then hands the image
+    // over to the graphics queue: still in `TRANSFER_DST_OPTIMAL` when mipmaps
+    // are blitted there, otherwise ready to be sampled.
+    let (layout, dst_stage_mask, dst_access_mask) = if linear_blit {
+        (
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::AccessFlags::TRANSFER_READ | vk::AccessFlags::TRANSFER_WRITE,
+        )
+    } else {
+        (
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            vk::PipelineStageFlags::FRAGMENT_SHADER,
+            vk::AccessFlags::SHADER_READ,
+        )
+    };
+
+    let transfer_commands = begin_transfer_commands(device, data)?;
+    let label = begin_label(device, transfer_commands, "upload texture", TRANSFER_COLOR);
+
+    cmd_transition_image_layout(
         device,
-        data,
-        data.texture_image,
-        vk::Format::R8G8B8A8_SRGB,
+        transfer_commands,
+        image,
         vk::ImageLayout::UNDEFINED,
         vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+        mip_levels,
     )?;
 
-    copy_buffer_to_image(
+    copy_buffer_to_image(device, transfer_commands, *staging_buffer, image, &regions);
+
+    let subresource = vk::ImageSubresourceRange::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .base_mip_level(0)
+        .level_count(mip_levels)
+        .base_array_layer(0)
+        .layer_count(1);
+
+    // A shared family has no ownership to transfer, so the release barrier
+    // alone transitions the layout and the semaphore orders the rest.
+    let (src_family, dst_family) = if indices.shared_transfer() {
+        (vk::QUEUE_FAMILY_IGNORED, vk::QUEUE_FAMILY_IGNORED)
+    } else {
+        (indices.transfer, indices.graphics)
+    };
+
+    let mut barrier = vk::ImageMemoryBarrier::builder()
+        .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+        .new_layout(layout)
+        .src_queue_family_index(src_family)
+        .dst_queue_family_index(dst_family)
+        .image(image)
+        .subresource_range(subresource)
+        .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+        .dst_access_mask(vk::AccessFlags::empty());
+
+    // Release ownership on the transfer queue...
+    device.cmd_pipeline_barrier(
+        transfer_commands,
+        vk::PipelineStageFlags::TRANSFER,
+        vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+        vk::DependencyFlags::empty(),
+        &[] as &[vk::MemoryBarrier],
+        &[] as &[vk::BufferMemoryBarrier],
+        &[barrier],
+    );
+    drop(label);
+
+    let graphics_commands = begin_single_time_commands(device, data)?;
+
+    // ...and acquire it with a matching barrier on the graphics queue.
+    if !indices.shared_transfer() {
+        barrier.src_access_mask = vk::AccessFlags::empty();
+        barrier.dst_access_mask = dst_access_mask;
+
+        device.cmd_pipeline_barrier(
+            graphics_commands,
+            vk::PipelineStageFlags::TOP_OF_PIPE,
+            dst_stage_mask,
+            vk::DependencyFlags::empty(),
+            &[] as &[vk::MemoryBarrier],
+            &[] as &[vk::BufferMemoryBarrier],
+            &[barrier],
+        );
+    }
+
+    if linear_blit {
+        let _label = begin_label(device, graphics_commands, "generate mipmaps", TRANSFER_COLOR);
+        generate_mipmaps(device, graphics_commands, image, width, height, mip_levels);
+    }
+
+    end_transfer_then_graphics_commands(
         device,
         data,
-        staging_buffer,
-        data.texture_image,
-        width,
-        height,
+        transfer_commands,
+        graphics_commands,
+        dst_stage_mask,
     )?;
 
-    transition_image_layout(
+    Ok(())
+}
+
+/// Records blits filling mip levels `1..mip_levels` from each previous
+/// level, leaving the whole image in `SHADER_READ_ONLY_OPTIMAL`.
+///
+/// Level 0 must hold the image in `TRANSFER_DST_OPTIMAL`, and the format must
+/// support linear-filter blits. Blits need a graphics queue.
+unsafe fn generate_mipmaps(
+    device: &Device,
+    command_buffer: vk::CommandBuffer,
+    image: vk::Image,
+    width: u32,
+    height: u32,
+    mip_levels: u32,
+) {
+    let subresource = vk::ImageSubresourceRange::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .base_array_layer(0)
+        .layer_count(1)
+        .level_count(1);
+
+    let mut barrier = vk::ImageMemoryBarrier::builder()
+        .image(image)
+        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .subresource_range(subresource);
+
+    let mut mip_width = width;
+    let mut mip_height = height;
+
+    for i in 1..mip_levels {
+        barrier.subresource_range.base_mip_level = i - 1;
+        barrier.old_layout = vk::ImageLayout::TRANSFER_DST_OPTIMAL;
+        barrier.new_layout = vk::ImageLayout::TRANSFER_SRC_OPTIMAL;
+        barrier.src_access_mask = vk::AccessFlags::TRANSFER_WRITE;
+        barrier.dst_access_mask = vk::AccessFlags::TRANSFER_READ;
+
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::DependencyFlags::empty(),
+            &[] as &[vk::MemoryBarrier],
+            &[] as &[vk::BufferMemoryBarrier],
+            &[barrier],
+        );
+
+        let src_subresource = vk::ImageSubresourceLayers::builder()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .mip_level(i - 1)
+            .base_array_layer(0)
+            .layer_count(1);
+
+        let dst_subresource = vk::ImageSubresourceLayers::builder()
+            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .mip_level(i)
+            .base_array_layer(0)
+            .layer_count(1);
+
+        let next_width = (mip_width / 2).max(1);
+        let next_height = (mip_height / 2).max(1);
+
+        let blit = vk::ImageBlit::builder()
+            .src_offsets([
+                vk::Offset3D { x: 0, y: 0, z: 0 },
+                vk::Offset3D {
+                    x: mip_width as i32,
+                    y: mip_height as i32,
+                    z: 1,
+                },
+            ])
+            .src_subresource(src_subresource)
+            .dst_offsets([
+                vk::Offset3D { x: 0, y: 0, z: 0 },
+                vk::Offset3D {
+                    x: next_width as i32,
+                    y: next_height as i32,
+                    z: 1,
+                },
+            ])
+            .dst_subresource(dst_subresource);
+
+        device.cmd_blit_image(
+            command_buffer,
+            image,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            image,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            &[blit],
+            vk::Filter::LINEAR,
+        );
+
+        barrier.old_layout = vk::ImageLayout::TRANSFER_SRC_OPTIMAL;
+        barrier.new_layout = vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL;
+        barrier.src_access_mask = vk::AccessFlags::TRANSFER_READ;
+        barrier.dst_access_mask = vk::AccessFlags::SHADER_READ;
+
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::FRAGMENT_SHADER,
+            vk::DependencyFlags::empty(),
+            &[] as &[vk::MemoryBarrier],
+            &[] as &[vk::BufferMemoryBarrier],
+            &[barrier],
+        );
+
+        mip_width = next_width;
+        mip_height = next_height;
+    }
+
+    // The last level is only ever blitted into.
+    barrier.subresource_range.base_mip_level = mip_levels - 1;
+    barrier.old_layout = vk::ImageLayout::TRANSFER_DST_OPTIMAL;
+    barrier.new_layout = vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL;
+    barrier.src_access_mask = vk::AccessFlags::TRANSFER_WRITE;
+    barrier.dst_access_mask = vk::AccessFlags::SHADER_READ;
+
+    device.cmd_pipeline_barrier(
+        command_buffer,
+        vk::PipelineStageFlags::TRANSFER,
+        vk::PipelineStageFlags::FRAGMENT_SHADER,
+        vk::DependencyFlags::empty(),
+        &[] as &[vk::MemoryBarrier],
+        &[] as &[vk::BufferMemoryBarrier],
+        &[barrier],
+    );
+}
+
+/// Halves an RGBA8 sRGB image with a 2x2 box filter, averaging color in
+/// linear space the way a linear blit would.
+fn downsample(pixels: &[u8], width: u32, height: u32) -> (Vec<u8>, u32, u32) {
+    let next_width = (width / 2).max(1);
+    let next_height = (height / 2).max(1);
+
+    let mut next = Vec::with_capacity((next_width * next_height * 4) as usize);
+    for y in 0..next_height {
+        let rows = [(2 * y).min(height - 1), (2 * y + 1).min(height - 1)];
+        for x in 0..next_width {
+            let columns = [(2 * x).min(width - 1), (2 * x + 1).min(width - 1)];
+            for c in 0..4 {
+                let mut sum = 0.0;
+                for row in rows {
+                    for column in columns {
+                        let value = pixels[((row * width + column) * 4 + c) as usize];
+                        sum += if c < 3 {
+                            srgb_to_linear(value)
+                        } else {
+                            value as f32 / 255.0
+                        };
+                    }
+                }
+
+                let average = sum / 4.0;
+                next.push(if c < 3 {
+                    linear_to_srgb(average)
+                } else {
+                    (average * 255.0).round() as u8
+                });
+            }
+        }
+    }
+
+    (next, next_width, next_height)
+}
+
+fn srgb_to_linear(value: u8) -> f32 {
+    let value = value as f32 / 255.0;
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f32) -> u8 {
+    let value = if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    };
+    (value * 255.0).round() as u8
+}
+
+pub unsafe fn create_texture_image_view(device: &SharedDevice, data: &mut AppData) -> Result<()> {
+    data.texture_image_view = create_image_view(
         device,
-        data,
-        data.texture_image,
+        "texture view",
+        *data.texture_image,
         vk::Format::R8G8B8A8_SRGB,
-        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-        vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        vk::ImageAspectFlags::COLOR,
+        data.texture_mip_levels,
     )?;
 
-    device.destroy_buffer(staging_buffer, None);
-    device.free_memory(staging_buffer_memory, None);
+    Ok(())
+}
+
+/// Filtering and addressing used when sampling textures.
+#[derive(Copy, Clone, Debug)]
+pub struct SamplerOptions {
+    pub mag_filter: vk::Filter,
+    pub min_filter: vk::Filter,
+    pub mipmap_mode: vk::SamplerMipmapMode,
+    pub address_mode: vk::SamplerAddressMode,
+    pub border_color: vk::BorderColor,
+    /// Requires the `sampler_anisotropy` device feature.
+    pub max_anisotropy: Option<f32>,
+}
+
+impl Default for SamplerOptions {
+    fn default() -> Self {
+        Self {
+            mag_filter: vk::Filter::LINEAR,
+            min_filter: vk::Filter::LINEAR,
+            mipmap_mode: vk::SamplerMipmapMode::LINEAR,
+            address_mode: vk::SamplerAddressMode::REPEAT,
+            border_color: vk::BorderColor::INT_OPAQUE_BLACK,
+            max_anisotropy: None,
+        }
+    }
+}
+
+pub unsafe fn create_texture_sampler(device: &SharedDevice, data: &mut AppData) -> Result<()> {
+    let mut options = data.sampler_options;
+    if options.max_anisotropy.is_some() && data.features.core.sampler_anisotropy != vk::TRUE {
+        warn!("Anisotropic filtering requested but the device feature isn't enabled.");
+        options.max_anisotropy = None;
+    }
+
+    let info = vk::SamplerCreateInfo::builder()
+        .mag_filter(options.mag_filter)
+        .min_filter(options.min_filter)
+        .address_mode_u(options.address_mode)
+        .address_mode_v(options.address_mode)
+        .address_mode_w(options.address_mode)
+        .anisotropy_enable(options.max_anisotropy.is_some())
+        .max_anisotropy(options.max_anisotropy.unwrap_or(1.0))
+        .border_color(options.border_color)
+        .unnormalized_coordinates(false)
+        .compare_enable(false)
+        .compare_op(vk::CompareOp::ALWAYS)
+        .mipmap_mode(options.mipmap_mode)
+        .mip_lod_bias(0.0)
+        .min_lod(0.0)
+        .max_lod(vk::LOD_CLAMP_NONE);
+
+    data.texture_sampler = Owned::new(device, device.create_sampler(&info, None)?);
+    set_object_name(device, *data.texture_sampler, "texture sampler");
 
     Ok(())
 }
 
 pub unsafe fn create_image(
     instance: &Instance,
-    device: &Device,
-    data: &AppData,
+    device: &SharedDevice,
+    data: &mut AppData,
+    name: &str,
     width: u32,
     height: u32,
+    mip_levels: u32,
+    samples: vk::SampleCountFlags,
     format: vk::Format,
     tiling: vk::ImageTiling,
     usage: vk::ImageUsageFlags,
     properties: vk::MemoryPropertyFlags,
-) -> Result<(vk::Image, vk::DeviceMemory)> {
+) -> Result<Image> {
     let info = vk::ImageCreateInfo::builder()
         .image_type(vk::ImageType::_2D)
         .extent(vk::Extent3D {
@@ -104,42 +468,170 @@ pub unsafe fn create_image(
             height,
             depth: 1,
         })
-        .mip_levels(1)
+        .mip_levels(mip_levels)
         .array_layers(1)
         .format(format)
         .tiling(tiling)
         .initial_layout(vk::ImageLayout::UNDEFINED)
         .usage(usage)
-        .samples(vk::SampleCountFlags::_1)
+        .samples(samples)
         .sharing_mode(vk::SharingMode::EXCLUSIVE);
 
-    let image = device.create_image(&info, None)?;
+    let image = Image::new(&data.allocator, &info, properties)?;
+    set_object_name(device, *image, name);
+
+    Ok(image)
+}
+
+pub unsafe fn create_image_view(
+    device: &SharedDevice,
+    name: &str,
+    image: vk::Image,
+    format: vk::Format,
+    aspects: vk::ImageAspectFlags,
+    mip_levels: u32,
+) -> Result<Owned<vk::ImageView>> {
+    let subresource_range = vk::ImageSubresourceRange::builder()
+        .aspect_mask(aspects)
+        .base_mip_level(0)
+        .level_count(mip_levels)
+        .base_array_layer(0)
+        .layer_count(1);
 
-    let requirements = device.get_image_memory_requirements(image);
+    let info = vk::ImageViewCreateInfo::builder()
+        .image(image)
+        .view_type(vk::ImageViewType::_2D)
+        .format(format)
+        .subresource_range(subresource_range);
 
-    let info = vk::MemoryAllocateInfo::builder()
-        .allocation_size(requirements.size)
-        .memory_type_index(get_memory_type_index(
-            instance,
-            data,
-            properties,
-            requirements,
-        )?);
+    let view = Owned::new(device, device.create_image_view(&info, None)?);
+    set_object_name(device, *view, name);
 
-    let image_memory = device.allocate_memory(&info, None)?;
+    Ok(view)
+}
 
-    device.bind_image_memory(image, image_memory, 0)?;
+/// Creates the transient multisampled color target that is resolved into the
+/// swapchain image. Nothing is created without multisampling.
+pub unsafe fn create_color_objects(
+    instance: &Instance,
+    device: &SharedDevice,
+    data: &mut AppData,
+) -> Result<()> {
+    if data.msaa_samples == vk::SampleCountFlags::_1 {
+        return Ok(());
+    }
+
+    data.color_image = create_image(
+        instance,
+        device,
+        data,
+        "msaa color",
+        data.swapchain_extent.width,
+        data.swapchain_extent.height,
+        1,
+        data.msaa_samples,
+        data.swapchain_format,
+        vk::ImageTiling::OPTIMAL,
+        vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSIENT_ATTACHMENT,
+        vk::MemoryPropertyFlags::DEVICE_LOCAL,
+    )?;
 
-    Ok((image, image_memory))
+    data.color_image_view = create_image_view(
+        device,
+        "msaa color view",
+        *data.color_image,
+        data.swapchain_format,
+        vk::ImageAspectFlags::COLOR,
+        1,
+    )?;
+
+    Ok(())
 }
 
-pub unsafe fn transition_image_layout(
-    device: &Device,
+pub unsafe fn create_depth_objects(
+    instance: &Instance,
+    device: &SharedDevice,
+    data: &mut AppData,
+) -> Result<()> {
+    let format = get_depth_format(instance, data)?;
+
+    data.depth_image = create_image(
+        instance,
+        device,
+        data,
+        "depth",
+        data.swapchain_extent.width,
+        data.swapchain_extent.height,
+        1,
+        data.msaa_samples,
+        format,
+        vk::ImageTiling::OPTIMAL,
+        vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+        vk::MemoryPropertyFlags::DEVICE_LOCAL,
+    )?;
+
+    let mut aspects = vk::ImageAspectFlags::DEPTH;
+    if has_stencil_component(format) {
+        aspects |= vk::ImageAspectFlags::STENCIL;
+    }
+
+    data.depth_image_view =
+        create_image_view(device, "depth view", *data.depth_image, format, aspects, 1)?;
+
+    Ok(())
+}
+
+/// Picks the preferred depth format with optimal-tiling attachment support.
+pub unsafe fn get_depth_format(instance: &Instance, data: &AppData) -> Result<vk::Format> {
+    let candidates = &[
+        vk::Format::D32_SFLOAT,
+        vk::Format::D24_UNORM_S8_UINT,
+        vk::Format::D16_UNORM,
+    ];
+
+    get_supported_format(
+        instance,
+        data,
+        candidates,
+        vk::ImageTiling::OPTIMAL,
+        vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT,
+    )
+}
+
+pub unsafe fn get_supported_format(
+    instance: &Instance,
     data: &AppData,
+    candidates: &[vk::Format],
+    tiling: vk::ImageTiling,
+    features: vk::FormatFeatureFlags,
+) -> Result<vk::Format> {
+    candidates
+        .iter()
+        .cloned()
+        .find(|f| {
+            let properties =
+                instance.get_physical_device_format_properties(data.physical_device, *f);
+            match tiling {
+                vk::ImageTiling::LINEAR => properties.linear_tiling_features.contains(features),
+                vk::ImageTiling::OPTIMAL => properties.optimal_tiling_features.contains(features),
+                _ => false,
+            }
+        })
+        .ok_or_else(|| anyhow!("Failed to find supported format!"))
+}
+
+pub fn has_stencil_component(format: vk::Format) -> bool {
+    format == vk::Format::D32_SFLOAT_S8_UINT || format == vk::Format::D24_UNORM_S8_UINT
+}
+
+/// Records a layout transition of every mip level of a color image.
+pub unsafe fn cmd_transition_image_layout(
+    device: &Device,
+    command_buffer: vk::CommandBuffer,
     image: vk::Image,
-    format: vk::Format,
     old_layout: vk::ImageLayout,
     new_layout: vk::ImageLayout,
+    mip_levels: u32,
 ) -> Result<()> {
     let (src_access_mask, dst_access_mask, src_stage_mask, dst_stage_mask) =
         match (old_layout, new_layout) {
@@ -158,12 +650,10 @@ pub unsafe fn transition_image_layout(
             _ => return Err(anyhow!("Unsupported image layout transition!")),
         };
 
-    let command_buffer = begin_single_time_commands(device, data)?;
-
     let subresource = vk::ImageSubresourceRange::builder()
         .aspect_mask(vk::ImageAspectFlags::COLOR)
         .base_mip_level(0)
-        .level_count(1)
+        .level_count(mip_levels)
         .base_array_layer(0)
         .layer_count(1);
 
@@ -187,48 +677,48 @@ pub unsafe fn transition_image_layout(
         &[barrier],
     );
 
-    end_single_time_commands(device, data, command_buffer)?;
-
     Ok(())
 }
 
+/// Records a copy of tightly packed mip levels, given as
+/// `(offset, width, height)`, from `buffer` into consecutive levels of `image`.
 unsafe fn copy_buffer_to_image(
     device: &Device,
-    data: &AppData,
+    command_buffer: vk::CommandBuffer,
     buffer: vk::Buffer,
     image: vk::Image,
-    width: u32,
-    height: u32,
-) -> Result<()> {
-    let command_buffer = begin_single_time_commands(device, data)?;
-
-    let subresource = vk::ImageSubresourceLayers::builder()
-        .aspect_mask(vk::ImageAspectFlags::COLOR)
-        .mip_level(0)
-        .base_array_layer(0)
-        .layer_count(1);
-
-    let region = vk::BufferImageCopy::builder()
-        .buffer_offset(0)
-        .buffer_row_length(0)
-        .buffer_image_height(0)
-        .image_subresource(subresource)
-        .image_offset(vk::Offset3D { x: 0, y: 0, z: 0 })
-        .image_extent(vk::Extent3D {
-            width,
-            height,
-            depth: 1,
-        });
+    levels: &[(u64, u32, u32)],
+) {
+    let regions = levels
+        .iter()
+        .enumerate()
+        .map(|(level, (offset, width, height))| {
+            let subresource = vk::ImageSubresourceLayers::builder()
+                .aspect_mask(vk::ImageAspectFlags::COLOR)
+                .mip_level(level as u32)
+                .base_array_layer(0)
+                .layer_count(1);
+
+            vk::BufferImageCopy::builder()
+                .buffer_offset(*offset)
+                .buffer_row_length(0)
+                .buffer_image_height(0)
+                .image_subresource(subresource)
+                .image_offset(vk::Offset3D { x: 0, y: 0, z: 0 })
+                .image_extent(vk::Extent3D {
+                    width: *width,
+                    height: *height,
+                    depth: 1,
+                })
+                .build()
+        })
+        .collect::<Vec<_>>();
 
     device.cmd_copy_buffer_to_image(
         command_buffer,
         buffer,
         image,
         vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-        &[region],
+        &regions,
     );
-
-    end_single_time_commands(device, data, command_buffer)?;
-
-    Ok(())
 }
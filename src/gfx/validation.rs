@@ -0,0 +1,480 @@
+//! Validation layer settings and `VK_EXT_validation_features` modes.
+
+use anyhow::{anyhow, Error, Result};
+use log::*;
+use std::ffi::CStr;
+use std::fmt;
+use std::os::raw::{c_char, c_void};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use vulkanalia::vk;
+
+type Severity = vk::DebugUtilsMessageSeverityFlagsEXT;
+type MessageType = vk::DebugUtilsMessageTypeFlagsEXT;
+
+/// The most messages a [`MessageCollector`] holds before dropping new ones.
+const MAX_MESSAGES: usize = 1024;
+
+/// Deprecated in favor of `VK_EXT_layer_settings`, which older validation
+/// layers don't provide.
+#[allow(deprecated)]
+pub const VALIDATION_FEATURES_EXTENSION: vk::Extension = vk::EXT_VALIDATION_FEATURES_EXTENSION;
+
+/// Whether to load the validation layer, which extra checks to turn on and
+/// which messages to report.
+///
+/// Parses from a comma separated list of modes like `gpu,best-practices`,
+/// which enables validation unless the list includes `off`. Including
+/// `panic` turns on `panic_on_error`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ValidationOptions {
+    pub enabled: bool,
+    /// Instruments shaders to check descriptor indexing and buffer accesses.
+    pub gpu_assisted: bool,
+    pub best_practices: bool,
+    /// Checks for hazards between commands, such as missing barriers.
+    pub synchronization: bool,
+    /// Forwards `debugPrintfEXT` output from shaders as info messages. Can't
+    /// be combined with GPU-assisted validation.
+    pub debug_printf: bool,
+    /// The message severities reported to the log.
+    pub severity: Severity,
+    /// The message types reported to the log.
+    pub message_type: MessageType,
+    /// Panics once control returns to the app after a validation error.
+    pub panic_on_error: bool,
+    /// A file of message IDs to ignore, one per line.
+    pub allowlist: Option<PathBuf>,
+}
+
+impl Default for ValidationOptions {
+    /// Validation is enabled in debug builds and reports every message.
+    fn default() -> Self {
+        Self {
+            enabled: cfg!(debug_assertions),
+            gpu_assisted: false,
+            best_practices: false,
+            synchronization: false,
+            debug_printf: false,
+            severity: Severity::all(),
+            message_type: MessageType::all(),
+            panic_on_error: false,
+            allowlist: None,
+        }
+    }
+}
+
+impl ValidationOptions {
+    /// The validation features to enable, dropping debug printf if it
+    /// conflicts with GPU-assisted validation.
+    pub fn enabled_features(&self) -> Vec<vk::ValidationFeatureEnableEXT> {
+        let mut features = Vec::new();
+
+        if self.gpu_assisted {
+            features.push(vk::ValidationFeatureEnableEXT::GPU_ASSISTED);
+            features.push(vk::ValidationFeatureEnableEXT::GPU_ASSISTED_RESERVE_BINDING_SLOT);
+        }
+        if self.best_practices {
+            features.push(vk::ValidationFeatureEnableEXT::BEST_PRACTICES);
+        }
+        if self.synchronization {
+            features.push(vk::ValidationFeatureEnableEXT::SYNCHRONIZATION_VALIDATION);
+        }
+        if self.debug_printf {
+            if self.gpu_assisted {
+                warn!("Debug printf can't be used with GPU-assisted validation; ignoring it.");
+            } else {
+                features.push(vk::ValidationFeatureEnableEXT::DEBUG_PRINTF);
+            }
+        }
+
+        features
+    }
+
+    /// Reports messages of at least `severity` (`verbose`, `info`, `warning`
+    /// or `error`).
+    pub fn set_min_severity(&mut self, severity: &str) -> Result<()> {
+        let min = match severity.to_ascii_lowercase().as_str() {
+            "verbose" => Severity::VERBOSE,
+            "info" => Severity::INFO,
+            "warning" | "warn" => Severity::WARNING,
+            "error" => Severity::ERROR,
+            _ => return Err(anyhow!("Unknown validation severity `{}`.", severity)),
+        };
+
+        self.severity = Severity::all() & !Severity::from_bits_truncate(min.bits() - 1);
+        Ok(())
+    }
+
+    /// Reports only the comma separated message types (`general`,
+    /// `validation`, `performance` and `device-address-binding`).
+    pub fn set_message_types(&mut self, types: &str) -> Result<()> {
+        self.message_type = MessageType::empty();
+        for type_ in types.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+            self.message_type |= match type_.to_ascii_lowercase().as_str() {
+                "general" => MessageType::GENERAL,
+                "validation" => MessageType::VALIDATION,
+                "performance" => MessageType::PERFORMANCE,
+                "device-address-binding" => MessageType::DEVICE_ADDRESS_BINDING,
+                _ => return Err(anyhow!("Unknown validation message type `{}`.", type_)),
+            };
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for ValidationOptions {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut options = Self {
+            enabled: true,
+            ..Default::default()
+        };
+
+        for mode in s.split(',').map(str::trim).filter(|m| !m.is_empty()) {
+            match mode.to_ascii_lowercase().as_str() {
+                "on" | "1" | "true" => options.enabled = true,
+                "off" | "0" | "false" => options.enabled = false,
+                "gpu" | "gpu-assisted" => options.gpu_assisted = true,
+                "best-practices" => options.best_practices = true,
+                "sync" | "synchronization" => options.synchronization = true,
+                "printf" | "debug-printf" => options.debug_printf = true,
+                "panic" => options.panic_on_error = true,
+                _ => return Err(anyhow!("Unknown validation mode `{}`.", mode)),
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+/// An object referred to by a validation message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MessageObject {
+    pub object_type: vk::ObjectType,
+    pub handle: u64,
+    /// The debug name given to the object, if any.
+    pub name: Option<String>,
+}
+
+/// A message reported through the debug messenger.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ValidationMessage {
+    pub severity: Severity,
+    pub message_type: MessageType,
+    /// The name of the check, like `VUID-vkCmdDraw-None-02699`.
+    pub id_name: Option<String>,
+    pub id_number: i32,
+    pub message: String,
+    pub objects: Vec<MessageObject>,
+    /// The debug labels open on the queue, innermost first.
+    pub queue_labels: Vec<String>,
+    /// The debug labels open in the command buffer, innermost first.
+    pub command_buffer_labels: Vec<String>,
+}
+
+impl ValidationMessage {
+    unsafe fn new(
+        severity: Severity,
+        message_type: MessageType,
+        data: &vk::DebugUtilsMessengerCallbackDataEXT,
+    ) -> Self {
+        let objects = slice(data.objects, data.object_count)
+            .iter()
+            .map(|o| MessageObject {
+                object_type: o.object_type,
+                handle: o.object_handle,
+                name: string(o.object_name),
+            })
+            .collect();
+        let labels = |labels, count| {
+            slice::<vk::DebugUtilsLabelEXT>(labels, count)
+                .iter()
+                .filter_map(|l| string(l.label_name))
+                .collect()
+        };
+
+        Self {
+            severity,
+            message_type,
+            id_name: string(data.message_id_name),
+            id_number: data.message_id_number,
+            message: string(data.message).unwrap_or_default(),
+            objects,
+            queue_labels: labels(data.queue_labels, data.queue_label_count),
+            command_buffer_labels: labels(data.cmd_buf_labels, data.cmd_buf_label_count),
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity >= Severity::ERROR
+    }
+
+    /// Whether `id` is this message's ID name or number (in decimal or hex).
+    pub fn has_id(&self, id: &str) -> bool {
+        self.id_name.as_deref() == Some(id)
+            || id.parse::<i32>() == Ok(self.id_number)
+            || id
+                .strip_prefix("0x")
+                .and_then(|h| u32::from_str_radix(h, 16).ok())
+                .is_some_and(|n| n as i32 == self.id_number)
+    }
+}
+
+impl fmt::Display for ValidationMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{:?}] ", self.severity)?;
+        if let Some(id_name) = &self.id_name {
+            write!(f, "{} ", id_name)?;
+        }
+        write!(f, "({:#x}) {}", self.id_number as u32, self.message)?;
+        for label in &self.command_buffer_labels {
+            write!(f, "\n  in command buffer label `{}`", label)?;
+        }
+        Ok(())
+    }
+}
+
+unsafe fn slice<'a, T>(pointer: *const T, count: u32) -> &'a [T] {
+    if pointer.is_null() {
+        &[]
+    } else {
+        std::slice::from_raw_parts(pointer, count as usize)
+    }
+}
+
+unsafe fn string(pointer: *const c_char) -> Option<String> {
+    (!pointer.is_null()).then(|| CStr::from_ptr(pointer).to_string_lossy().into_owned())
+}
+
+/// Collects the warnings and errors sent to the debug messenger, which may
+/// call in from any thread, so that they can be inspected after rendering.
+///
+/// Less severe messages are only logged. Once [`MAX_MESSAGES`] are held, new
+/// ones are counted and dropped until the collector is drained.
+#[derive(Debug, Default)]
+pub struct MessageCollector {
+    messages: Mutex<Vec<ValidationMessage>>,
+    dropped: AtomicUsize,
+    /// The errors among the dropped messages.
+    dropped_errors: AtomicUsize,
+    allowlist: Vec<String>,
+    panic_on_error: bool,
+    /// Set when an error arrives in panic-on-error mode, until checked.
+    failed: AtomicBool,
+}
+
+impl MessageCollector {
+    /// Creates a collector, loading the allowlist file if there is one.
+    pub fn new(options: &ValidationOptions) -> Result<Self> {
+        let allowlist = match &options.allowlist {
+            Some(path) => load_allowlist(path)?,
+            None => Vec::new(),
+        };
+
+        Ok(Self {
+            allowlist,
+            panic_on_error: options.panic_on_error,
+            ..Default::default()
+        })
+    }
+
+    /// Whether a message is on the allowlist.
+    pub fn is_allowed(&self, message: &ValidationMessage) -> bool {
+        self.allowlist.iter().any(|id| message.has_id(id))
+    }
+
+    /// Removes and returns the messages collected so far.
+    pub fn drain(&self) -> Vec<ValidationMessage> {
+        self.dropped.store(0, Ordering::SeqCst);
+        self.dropped_errors.store(0, Ordering::SeqCst);
+        std::mem::take(&mut *self.messages.lock().unwrap())
+    }
+
+    /// The number of messages dropped since the last drain because the
+    /// collector was full.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::SeqCst)
+    }
+
+    /// Drains the collected messages, failing with all of them.
+    pub fn assert_clean(&self) -> Result<()> {
+        let dropped = self.dropped();
+        let mut problems = self
+            .drain()
+            .into_iter()
+            .map(|m| m.to_string())
+            .collect::<Vec<_>>();
+
+        if problems.is_empty() {
+            return Ok(());
+        }
+
+        let count = problems.len() + dropped;
+        if dropped > 0 {
+            problems.push(format!("... and {} more", dropped));
+        }
+        Err(anyhow!(
+            "{} validation message(s):\n{}",
+            count,
+            problems.join("\n")
+        ))
+    }
+
+    /// Panics if an error was reported in panic-on-error mode since the last
+    /// check.
+    ///
+    /// The debug messenger can't unwind through the driver, so the app calls
+    /// this whenever control returns from Vulkan to it.
+    pub fn check_errors(&self) {
+        if self.failed.swap(false, Ordering::SeqCst) {
+            let messages = self.messages.lock().unwrap();
+            let mut errors = messages
+                .iter()
+                .filter(|m| m.is_error())
+                .map(|m| m.to_string())
+                .collect::<Vec<_>>();
+            let dropped = self.dropped_errors.load(Ordering::SeqCst);
+            if dropped > 0 {
+                errors.push(format!("... and {} more dropped by the full collector", dropped));
+            }
+            panic!("Validation error(s):\n{}", errors.join("\n"));
+        }
+    }
+
+    fn push(&self, message: ValidationMessage) {
+        if self.panic_on_error && message.is_error() {
+            self.failed.store(true, Ordering::SeqCst);
+        }
+        if message.severity < Severity::WARNING {
+            return;
+        }
+
+        let mut messages = self.messages.lock().unwrap();
+        if messages.len() < MAX_MESSAGES {
+            messages.push(message);
+        } else {
+            self.dropped.fetch_add(1, Ordering::SeqCst);
+            if message.is_error() {
+                self.dropped_errors.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+/// Reads message IDs (names, decimal or hex numbers) from a file, one per
+/// line. Blank lines and anything after a `#` are ignored.
+pub fn load_allowlist(path: &Path) -> Result<Vec<String>> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("Failed to read allowlist `{}`: {}", path.display(), e))?;
+
+    Ok(contents
+        .lines()
+        .map(|l| l.split('#').next().unwrap_or_default().trim())
+        .filter(|l| !l.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+/// Logs a message from the debug messenger and records it in the
+/// [`MessageCollector`] passed as its user data.
+pub(crate) extern "system" fn debug_callback(
+    severity: vk::DebugUtilsMessageSeverityFlagsEXT,
+    type_: vk::DebugUtilsMessageTypeFlagsEXT,
+    data: *const vk::DebugUtilsMessengerCallbackDataEXT,
+    collector: *mut c_void,
+) -> vk::Bool32 {
+    let message = unsafe { ValidationMessage::new(severity, type_, &*data) };
+    let collector = unsafe { (collector as *const MessageCollector).as_ref() };
+
+    if collector.is_some_and(|c| c.is_allowed(&message)) {
+        trace!("(allowed) ({:?}) {}", type_, message.message);
+        return vk::FALSE;
+    }
+
+    if severity >= vk::DebugUtilsMessageSeverityFlagsEXT::ERROR {
+        error!("({:?}) {}", type_, message.message);
+    } else if severity >= vk::DebugUtilsMessageSeverityFlagsEXT::WARNING {
+        warn!("({:?}) {}", type_, message.message);
+    } else if severity >= vk::DebugUtilsMessageSeverityFlagsEXT::INFO {
+        debug!("({:?}) {}", type_, message.message);
+    } else {
+        trace!("({:?}) {}", type_, message.message);
+    }
+
+    if let Some(collector) = collector {
+        collector.push(message);
+    }
+
+    vk::FALSE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(severity: Severity, text: &str) -> ValidationMessage {
+        ValidationMessage {
+            severity,
+            message_type: MessageType::VALIDATION,
+            id_name: None,
+            id_number: 0,
+            message: text.into(),
+            objects: Vec::new(),
+            queue_labels: Vec::new(),
+            command_buffer_labels: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn collector_keeps_warnings_and_errors() {
+        let collector = MessageCollector::default();
+        collector.push(message(Severity::VERBOSE, "verbose"));
+        collector.push(message(Severity::INFO, "info"));
+        collector.push(message(Severity::WARNING, "warning"));
+        collector.push(message(Severity::ERROR, "error"));
+
+        let messages = collector.drain();
+        let texts = messages.iter().map(|m| m.message.as_str()).collect::<Vec<_>>();
+        assert_eq!(texts, ["warning", "error"]);
+        assert!(collector.assert_clean().is_ok());
+    }
+
+    #[test]
+    fn collector_counts_messages_past_the_cap() {
+        let collector = MessageCollector::default();
+        for _ in 0..MAX_MESSAGES + 3 {
+            collector.push(message(Severity::WARNING, "warning"));
+        }
+        collector.push(message(Severity::ERROR, "error"));
+
+        assert_eq!(collector.dropped(), 4);
+        assert_eq!(collector.dropped_errors.load(Ordering::SeqCst), 1);
+
+        let error = collector.assert_clean().unwrap_err().to_string();
+        assert!(error.starts_with(&format!("{} validation message(s)", MAX_MESSAGES + 4)));
+        assert!(error.ends_with("... and 4 more"));
+
+        assert_eq!(collector.dropped(), 0);
+        assert!(collector.drain().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "... and 1 more dropped by the full collector")]
+    fn check_errors_reports_dropped_errors() {
+        let collector = MessageCollector {
+            panic_on_error: true,
+            ..Default::default()
+        };
+        for _ in 0..MAX_MESSAGES {
+            collector.push(message(Severity::WARNING, "warning"));
+        }
+        collector.push(message(Severity::ERROR, "error"));
+
+        collector.check_errors();
+    }
+}
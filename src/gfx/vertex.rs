@@ -1,7 +1,9 @@
-use std::mem::size_of;
+use std::hash::{Hash, Hasher};
+use std::mem::{size_of, size_of_val};
 
+use crate::gfx::debug::*;
 use crate::gfx::*;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use cgmath::{vec2, vec3};
 use std::ptr::copy_nonoverlapping as memcpy;
 use vulkanalia::vk;
@@ -9,30 +11,39 @@ use vulkanalia::vk;
 use crate::AppData;
 
 use self::device::QueueFamilyIndices;
+use self::pipeline::{FRAG_SHADER, VERT_SHADER};
+use self::resource::{Buffer, Owned, SharedDevice};
 
 pub type Vec2 = cgmath::Vector2<f32>;
 pub type Vec3 = cgmath::Vector3<f32>;
+pub type Vec4 = cgmath::Vector4<f32>;
 pub type Mat4 = cgmath::Matrix4<f32>;
 
+/// The quad drawn when no model has been loaded.
 pub static VERTICES: [Vertex; 4] = [
-    Vertex::new(vec2(-0.5, -0.5), vec3(1.0, 0.0, 0.0)),
-    Vertex::new(vec2(0.5, -0.5), vec3(0.0, 1.0, 0.0)),
-    Vertex::new(vec2(0.5, 0.5), vec3(0.0, 0.0, 1.0)),
-    Vertex::new(vec2(-0.5, 0.5), vec3(1.0, 1.0, 1.0)),
+    Vertex::new(vec3(-0.5, -0.5, 0.0), vec3(1.0, 0.0, 0.0), vec2(1.0, 0.0)),
+    Vertex::new(vec3(0.5, -0.5, 0.0), vec3(0.0, 1.0, 0.0), vec2(0.0, 0.0)),
+    Vertex::new(vec3(0.5, 0.5, 0.0), vec3(0.0, 0.0, 1.0), vec2(0.0, 1.0)),
+    Vertex::new(vec3(-0.5, 0.5, 0.0), vec3(1.0, 1.0, 1.0), vec2(1.0, 1.0)),
 ];
 
-pub const INDICES: &[u16] = &[0, 1, 2, 2, 3, 0];
+pub const INDICES: &[u32] = &[0, 1, 2, 2, 3, 0];
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug)]
 pub struct Vertex {
-    pub pos: Vec2,
+    pub pos: Vec3,
     pub color: Vec3,
+    pub tex_coord: Vec2,
 }
 
 impl Vertex {
-    pub const fn new(pos: Vec2, color: Vec3) -> Self {
-        Self { pos, color }
+    pub const fn new(pos: Vec3, color: Vec3, tex_coord: Vec2) -> Self {
+        Self {
+            pos,
+            color,
+            tex_coord,
+        }
     }
 
     pub fn binding_description() -> vk::VertexInputBindingDescription {
@@ -43,11 +54,11 @@ impl Vertex {
             .build()
     }
 
-    pub fn attribute_descriptions() -> [vk::VertexInputAttributeDescription; 2] {
+    pub fn attribute_descriptions() -> [vk::VertexInputAttributeDescription; 3] {
         let pos = vk::VertexInputAttributeDescription::builder()
             .binding(0)
             .location(0)
-            .format(vk::Format::R32G32_SFLOAT)
+            .format(vk::Format::R32G32B32_SFLOAT)
             .offset(0)
             .build();
 
@@ -55,123 +66,131 @@ impl Vertex {
             .binding(0)
             .location(1)
             .format(vk::Format::R32G32B32_SFLOAT)
-            .offset(size_of::<Vec2>() as u32)
+            .offset(size_of::<Vec3>() as u32)
             .build();
 
-        [pos, color]
+        let tex_coord = vk::VertexInputAttributeDescription::builder()
+            .binding(0)
+            .location(2)
+            .format(vk::Format::R32G32_SFLOAT)
+            .offset((size_of::<Vec3>() + size_of::<Vec3>()) as u32)
+            .build();
+
+        [pos, color, tex_coord]
+    }
+}
+
+// Vertices are compared bitwise so they can be deduplicated through a `HashMap`.
+
+impl PartialEq for Vertex {
+    fn eq(&self, other: &Self) -> bool {
+        self.pos == other.pos && self.color == other.color && self.tex_coord == other.tex_coord
     }
 }
 
-pub unsafe fn create_vertex_buffer(
+impl Eq for Vertex {}
+
+impl Hash for Vertex {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.pos[0].to_bits().hash(state);
+        self.pos[1].to_bits().hash(state);
+        self.pos[2].to_bits().hash(state);
+        self.color[0].to_bits().hash(state);
+        self.color[1].to_bits().hash(state);
+        self.color[2].to_bits().hash(state);
+        self.tex_coord[0].to_bits().hash(state);
+        self.tex_coord[1].to_bits().hash(state);
+    }
+}
+
+/// Uploads `items` through a staging buffer into a new device-local buffer.
+pub unsafe fn create_device_local_buffer<T: Copy>(
     instance: &Instance,
-    device: &Device,
+    device: &SharedDevice,
     data: &mut AppData,
-) -> Result<()> {
-    let size = (size_of::<Vertex>() * VERTICES.len()) as u64;
+    name: &str,
+    items: &[T],
+    usage: vk::BufferUsageFlags,
+) -> Result<Buffer> {
+    let size = size_of_val(items) as u64;
 
-    let (staging_buffer, staging_buffer_memory) = create_buffer(
+    let staging_buffer = create_buffer(
         instance,
         device,
         data,
+        &format!("{} staging", name),
         size,
         vk::BufferUsageFlags::TRANSFER_SRC,
         vk::MemoryPropertyFlags::HOST_COHERENT | vk::MemoryPropertyFlags::HOST_VISIBLE,
     )?;
 
-    let memory = device.map_memory(staging_buffer_memory, 0, size, vk::MemoryMapFlags::empty())?;
+    let memory = staging_buffer.mapped_ptr()?;
 
-    memcpy(VERTICES.as_ptr(), memory.cast(), VERTICES.len());
+    memcpy(items.as_ptr(), memory.cast(), items.len());
 
-    device.unmap_memory(staging_buffer_memory);
-
-    let (vertex_buffer, vertex_buffer_memory) = create_buffer(
+    let buffer = create_buffer(
         instance,
         device,
         data,
+        name,
         size,
-        vk::BufferUsageFlags::TRANSFER_DST | vk::BufferUsageFlags::VERTEX_BUFFER,
+        vk::BufferUsageFlags::TRANSFER_DST | usage,
         vk::MemoryPropertyFlags::DEVICE_LOCAL,
     )?;
 
-    data.vertex_buffer = vertex_buffer;
-    data.vertex_buffer_memory = vertex_buffer_memory;
-
-    copy_buffer(device, data, staging_buffer, vertex_buffer, size)?;
+    copy_buffer(device, data, *staging_buffer, *buffer, size)?;
 
-    info!("After copy buffer");
-    device.destroy_buffer(staging_buffer, None);
-    device.free_memory(staging_buffer_memory, None);
-
-    Ok(())
+    Ok(buffer)
 }
 
 pub unsafe fn create_buffer(
     instance: &Instance,
-    device: &Device,
+    device: &SharedDevice,
     data: &mut AppData,
+    name: &str,
     size: vk::DeviceSize,
     usage: vk::BufferUsageFlags,
     properties: vk::MemoryPropertyFlags,
-) -> Result<(vk::Buffer, vk::DeviceMemory)> {
+) -> Result<Buffer> {
     let indices = QueueFamilyIndices::get(instance, data, data.physical_device)?;
     let binding = [indices.graphics, indices.transfer];
 
-    let buffer_info = vk::BufferCreateInfo::builder()
+    let mut buffer_info = vk::BufferCreateInfo::builder()
         .size(size)
         .usage(usage)
-        .sharing_mode(vk::SharingMode::CONCURRENT)
-        .queue_family_indices(&binding);
-
-    let buffer = device.create_buffer(&buffer_info, None)?;
-
-    let requirements = device.get_buffer_memory_requirements(buffer);
-
-    let memory_info = vk::MemoryAllocateInfo::builder()
-        .allocation_size(requirements.size)
-        .memory_type_index(get_memory_type_index(
-            instance,
-            data,
-            properties,
-            requirements,
-        )?);
-
-    let buffer_memory = device.allocate_memory(&memory_info, None)?;
+        .sharing_mode(vk::SharingMode::EXCLUSIVE);
+    if !indices.shared_transfer() {
+        buffer_info = buffer_info
+            .sharing_mode(vk::SharingMode::CONCURRENT)
+            .queue_family_indices(&binding);
+    }
 
-    device.bind_buffer_memory(buffer, buffer_memory, 0)?;
+    let buffer = Buffer::new(&data.allocator, &buffer_info, properties)?;
+    set_object_name(device, *buffer, name);
 
-    Ok((buffer, buffer_memory))
-}
-
-pub unsafe fn get_memory_type_index(
-    instance: &Instance,
-    data: &AppData,
-    properties: vk::MemoryPropertyFlags,
-    requirements: vk::MemoryRequirements,
-) -> Result<u32> {
-    let memory = instance.get_physical_device_memory_properties(data.physical_device);
-
-    (0..memory.memory_type_count)
-        .find(|i| {
-            let suitable = (requirements.memory_type_bits & (1 << i)) != 0;
-            let memory_type = memory.memory_types[*i as usize];
-            suitable && memory_type.property_flags.contains(properties)
-        })
-        .ok_or_else(|| anyhow!("Failed to find suitable memory type."))
+    Ok(buffer)
 }
 
+/// Copies between buffers on the transfer queue.
+///
+/// Buffers are created with `CONCURRENT` sharing between the graphics and
+/// transfer families (or `EXCLUSIVE` to a shared one), so no ownership
+/// transfer is needed afterwards.
 pub unsafe fn copy_buffer(
-    device: &Device,
+    device: &SharedDevice,
     data: &AppData,
     source: vk::Buffer,
     destination: vk::Buffer,
     size: vk::DeviceSize,
 ) -> Result<()> {
-    let command_buffer = begin_single_time_commands(device, data)?;
+    let command_buffer = begin_transfer_commands(device, data)?;
 
+    let label = begin_label(device, command_buffer, "copy buffer", TRANSFER_COLOR);
     let regions = vk::BufferCopy::builder().size(size);
     device.cmd_copy_buffer(command_buffer, source, destination, &[regions]);
+    drop(label);
 
-    end_single_time_commands(device, data, command_buffer)?;
+    end_transfer_commands(device, data, command_buffer)?;
 
     Ok(())
 }
@@ -179,10 +198,25 @@ pub unsafe fn copy_buffer(
 pub unsafe fn begin_single_time_commands(
     device: &Device,
     data: &AppData,
+) -> Result<vk::CommandBuffer> {
+    begin_one_time_commands(device, *data.command_pool)
+}
+
+/// Like [`begin_single_time_commands`], but for the transfer queue.
+pub unsafe fn begin_transfer_commands(
+    device: &Device,
+    data: &AppData,
+) -> Result<vk::CommandBuffer> {
+    begin_one_time_commands(device, *data.transfer_command_pool)
+}
+
+unsafe fn begin_one_time_commands(
+    device: &Device,
+    pool: vk::CommandPool,
 ) -> Result<vk::CommandBuffer> {
     let info = vk::CommandBufferAllocateInfo::builder()
         .level(vk::CommandBufferLevel::PRIMARY)
-        .command_pool(data.command_pool)
+        .command_pool(pool)
         .command_buffer_count(1);
 
     let command_buffer = device.allocate_command_buffers(&info)?[0];
@@ -195,144 +229,210 @@ pub unsafe fn begin_single_time_commands(
     Ok(command_buffer)
 }
 
+/// Submits one-off graphics commands and waits for them on a fence, so frames
+/// already in flight are not waited for.
 pub unsafe fn end_single_time_commands(
-    device: &Device,
+    device: &SharedDevice,
     data: &AppData,
     command_buffer: vk::CommandBuffer,
 ) -> Result<()> {
-    device.end_command_buffer(command_buffer)?;
+    let result = submit_and_wait(device, data.graphics_queue, command_buffer);
+    device.free_command_buffers(*data.command_pool, &[command_buffer]);
+    result
+}
 
-    let command_buffers = &[command_buffer];
-    let info = vk::SubmitInfo::builder()
-        .command_buffers(command_buffers);
+/// Submits one-off transfer commands and waits for them on a fence, leaving
+/// the graphics queue free to keep rendering.
+pub unsafe fn end_transfer_commands(
+    device: &SharedDevice,
+    data: &AppData,
+    command_buffer: vk::CommandBuffer,
+) -> Result<()> {
+    let result = submit_and_wait(device, data.transfer_queue, command_buffer);
+    device.free_command_buffers(*data.transfer_command_pool, &[command_buffer]);
+    result
+}
 
-    device.queue_submit(data.graphics_queue, &[info], vk::Fence::null())?;
-    device.queue_wait_idle(data.graphics_queue)?;
+/// Submits one-off transfer commands, then graphics commands that wait for
+/// them on a semaphore at `wait_stage` (typically to acquire ownership of what
+/// was uploaded), and waits for both on a fence.
+pub unsafe fn end_transfer_then_graphics_commands(
+    device: &SharedDevice,
+    data: &AppData,
+    transfer_command_buffer: vk::CommandBuffer,
+    graphics_command_buffer: vk::CommandBuffer,
+    wait_stage: vk::PipelineStageFlags,
+) -> Result<()> {
+    let result = submit_transfer_then_graphics(
+        device,
+        data,
+        transfer_command_buffer,
+        graphics_command_buffer,
+        wait_stage,
+    );
 
-    device.free_command_buffers(data.command_pool, &[command_buffer]);
+    device.free_command_buffers(*data.transfer_command_pool, &[transfer_command_buffer]);
+    device.free_command_buffers(*data.command_pool, &[graphics_command_buffer]);
 
-    Ok(())
+    result
 }
 
-
-pub unsafe fn create_index_buffer(
-    instance: &Instance,
-    device: &Device,
-    data: &mut AppData,
+unsafe fn submit_transfer_then_graphics(
+    device: &SharedDevice,
+    data: &AppData,
+    transfer_command_buffer: vk::CommandBuffer,
+    graphics_command_buffer: vk::CommandBuffer,
+    wait_stage: vk::PipelineStageFlags,
 ) -> Result<()> {
-    let size = (size_of::<u16>() * INDICES.len()) as u64;
+    device.end_command_buffer(transfer_command_buffer)?;
+    device.end_command_buffer(graphics_command_buffer)?;
 
-    let (staging_buffer, staging_buffer_memory) = create_buffer(
-        instance,
+    let semaphore = Owned::new(
         device,
-        data,
-        size,
-        vk::BufferUsageFlags::TRANSFER_SRC,
-        vk::MemoryPropertyFlags::HOST_COHERENT | vk::MemoryPropertyFlags::HOST_VISIBLE,
-    )?;
+        device.create_semaphore(&vk::SemaphoreCreateInfo::builder(), None)?,
+    );
+    let fence = Owned::new(
+        device,
+        device.create_fence(&vk::FenceCreateInfo::builder(), None)?,
+    );
 
-    let memory = device.map_memory(staging_buffer_memory, 0, size, vk::MemoryMapFlags::empty())?;
+    let semaphores = &[*semaphore];
+    let command_buffers = &[transfer_command_buffer];
+    let info = vk::SubmitInfo::builder()
+        .command_buffers(command_buffers)
+        .signal_semaphores(semaphores);
 
-    memcpy(INDICES.as_ptr(), memory.cast(), INDICES.len());
+    device.queue_submit(data.transfer_queue, &[info], vk::Fence::null())?;
 
-    device.unmap_memory(staging_buffer_memory);
+    let wait_stages = &[wait_stage];
+    let command_buffers = &[graphics_command_buffer];
+    let info = vk::SubmitInfo::builder()
+        .wait_semaphores(semaphores)
+        .wait_dst_stage_mask(wait_stages)
+        .command_buffers(command_buffers);
 
-    let (index_buffer, index_buffer_memory) = create_buffer(
-        instance,
-        device,
-        data,
-        size,
-        vk::BufferUsageFlags::TRANSFER_DST | vk::BufferUsageFlags::INDEX_BUFFER,
-        vk::MemoryPropertyFlags::DEVICE_LOCAL,
-    )?;
+    device.queue_submit(data.graphics_queue, &[info], *fence)?;
+    device.wait_for_fences(&[*fence], true, u64::MAX)?;
 
-    data.index_buffer = index_buffer;
-    data.index_buffer_memory = index_buffer_memory;
+    Ok(())
+}
 
-    copy_buffer(device, data, staging_buffer, index_buffer, size)?;
+unsafe fn submit_and_wait(
+    device: &SharedDevice,
+    queue: vk::Queue,
+    command_buffer: vk::CommandBuffer,
+) -> Result<()> {
+    device.end_command_buffer(command_buffer)?;
 
-    device.destroy_buffer(staging_buffer, None);
-    device.free_memory(staging_buffer_memory, None);
+    let fence = Owned::new(
+        device,
+        device.create_fence(&vk::FenceCreateInfo::builder(), None)?,
+    );
+
+    let command_buffers = &[command_buffer];
+    let info = vk::SubmitInfo::builder().command_buffers(command_buffers);
+
+    device.queue_submit(queue, &[info], *fence)?;
+    device.wait_for_fences(&[*fence], true, u64::MAX)?;
 
     Ok(())
 }
 
+/// The camera matrices, written once per frame in flight.
 #[repr(C)]
 #[derive(Copy, Clone, Debug)]
-pub struct UniformBufferObject {
-    pub model: Mat4,
+pub struct CameraUniform {
     pub view: Mat4,
     pub proj: Mat4,
 }
 
-pub unsafe fn create_descriptor_set_layout(device: &Device, data: &mut AppData) -> Result<()> {
-    let ubo_binding = vk::DescriptorSetLayoutBinding::builder()
-        .binding(0)
-        .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
-        .descriptor_count(1)
-        .stage_flags(vk::ShaderStageFlags::VERTEX);
+/// Per-object data pushed before each draw.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct ObjectConstants {
+    pub model: Mat4,
+    /// Multiplies the sampled texture color.
+    pub tint: Vec4,
+}
+
+pub unsafe fn create_descriptor_set_layout(
+    device: &SharedDevice,
+    data: &mut AppData,
+) -> Result<()> {
+    data.reflection = data.shaders.reflect(&[VERT_SHADER, FRAG_SHADER])?;
 
-    let bindings = &[ubo_binding];
-    let info = vk::DescriptorSetLayoutCreateInfo::builder().bindings(bindings);
+    let bindings = data.reflection.set_layout_bindings(0);
+    let info = vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings);
 
-    data.descriptor_set_layout = device.create_descriptor_set_layout(&info, None)?;
+    data.descriptor_set_layout =
+        Owned::new(device, device.create_descriptor_set_layout(&info, None)?);
+    set_object_name(device, *data.descriptor_set_layout, "descriptor set layout");
 
     Ok(())
 }
 
 pub unsafe fn create_uniform_buffers(
     instance: &Instance,
-    device: &Device,
+    device: &SharedDevice,
     data: &mut AppData,
 ) -> Result<()> {
     data.uniform_buffers.clear();
-    data.uniform_buffers_memory.clear();
 
-    for _ in 0..data.swapchain_images.len() {
-        let (uniform_buffer, uniform_buffer_memory) = create_buffer(
+    let size = data.reflection.binding(0, 0).map(|b| b.size);
+    if size != Some(size_of::<CameraUniform>() as u32) {
+        return Err(anyhow!(
+            "Camera uniform block is {:?} bytes in the shaders but {} bytes in `CameraUniform`.",
+            size,
+            size_of::<CameraUniform>()
+        ));
+    }
+
+    for i in 0..crate::MAX_FRAMES_IN_FLIGHT {
+        let uniform_buffer = create_buffer(
             instance,
             device,
             data,
-            size_of::<UniformBufferObject>() as u64,
+            &format!("camera uniforms {}", i),
+            size_of::<CameraUniform>() as u64,
             vk::BufferUsageFlags::UNIFORM_BUFFER,
             vk::MemoryPropertyFlags::HOST_COHERENT | vk::MemoryPropertyFlags::HOST_VISIBLE,
         )?;
 
         data.uniform_buffers.push(uniform_buffer);
-        data.uniform_buffers_memory.push(uniform_buffer_memory);
     }
 
     Ok(())
 }
 
-pub unsafe fn create_descriptor_pool(device: &Device, data: &mut AppData) -> Result<()> {
-    let ubo_size = vk::DescriptorPoolSize::builder()
-        .type_(vk::DescriptorType::UNIFORM_BUFFER)
-        .descriptor_count(data.swapchain_images.len() as u32);
-
-    let pool_sizes = &[ubo_size];
+pub unsafe fn create_descriptor_pool(device: &SharedDevice, data: &mut AppData) -> Result<()> {
+    let pool_sizes = data
+        .reflection
+        .pool_sizes(crate::MAX_FRAMES_IN_FLIGHT as u32);
     let info = vk::DescriptorPoolCreateInfo::builder()
-        .pool_sizes(pool_sizes)
-        .max_sets(data.swapchain_images.len() as u32);
+        .pool_sizes(&pool_sizes)
+        .max_sets(crate::MAX_FRAMES_IN_FLIGHT as u32);
 
-    data.descriptor_pool = device.create_descriptor_pool(&info, None)?;
+    data.descriptor_pool = Owned::new(device, device.create_descriptor_pool(&info, None)?);
+    set_object_name(device, *data.descriptor_pool, "descriptor pool");
 
     Ok(())
 }
 
-pub unsafe fn create_descriptor_sets(device: &Device, data: &mut AppData) -> Result<()> {
-    let layouts = vec![data.descriptor_set_layout; data.swapchain_images.len()];
+pub unsafe fn create_descriptor_sets(device: &SharedDevice, data: &mut AppData) -> Result<()> {
+    let layouts = vec![*data.descriptor_set_layout; crate::MAX_FRAMES_IN_FLIGHT];
     let info = vk::DescriptorSetAllocateInfo::builder()
-        .descriptor_pool(data.descriptor_pool)
+        .descriptor_pool(*data.descriptor_pool)
         .set_layouts(&layouts);
 
     data.descriptor_sets = device.allocate_descriptor_sets(&info)?;
 
-    for i in 0..data.swapchain_images.len() {
+    for i in 0..crate::MAX_FRAMES_IN_FLIGHT {
+        set_object_name(device, data.descriptor_sets[i], &format!("frame {} descriptors", i));
+
         let info = vk::DescriptorBufferInfo::builder()
-            .buffer(data.uniform_buffers[i])
+            .buffer(*data.uniform_buffers[i])
             .offset(0)
-            .range(size_of::<UniformBufferObject>() as u64);
+            .range(size_of::<CameraUniform>() as u64);
 
         let buffer_info = &[info];
         let ubo_write = vk::WriteDescriptorSet::builder()
@@ -342,7 +442,23 @@ pub unsafe fn create_descriptor_sets(device: &Device, data: &mut AppData) -> Res
             .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
             .buffer_info(buffer_info);
 
-        device.update_descriptor_sets(&[ubo_write], &[] as &[vk::CopyDescriptorSet]);
+        let info = vk::DescriptorImageInfo::builder()
+            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            .image_view(*data.texture_image_view)
+            .sampler(*data.texture_sampler);
+
+        let image_info = &[info];
+        let sampler_write = vk::WriteDescriptorSet::builder()
+            .dst_set(data.descriptor_sets[i])
+            .dst_binding(1)
+            .dst_array_element(0)
+            .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+            .image_info(image_info);
+
+        device.update_descriptor_sets(
+            &[ubo_write, sampler_write],
+            &[] as &[vk::CopyDescriptorSet],
+        );
     }
 
     Ok(())
@@ -0,0 +1,22 @@
+#![allow(
+    dead_code,
+    unused_variables,
+    clippy::too_many_arguments,
+    clippy::unnecessary_wraps,
+    clippy::missing_safety_doc
+)]
+
+pub mod gfx;
+
+use vulkanalia::prelude::v1_0::*;
+use vulkanalia::Version;
+
+use gfx::*;
+
+const PORTABILITY_MACOS_VERSION: Version = Version::new(1, 3, 216);
+/// The newest Vulkan API version the app is written against.
+const MAX_API_VERSION: Version = Version::new(1, 3, 0);
+const VALIDATION_LAYER: vk::ExtensionName =
+    vk::ExtensionName::from_bytes(b"VK_LAYER_KHRONOS_validation");
+const DEVICE_EXTENSIONS: &[vk::ExtensionName] = &[vk::KHR_SWAPCHAIN_EXTENSION.name];
+const MAX_FRAMES_IN_FLIGHT: usize = 2;
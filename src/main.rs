@@ -5,30 +5,40 @@
     clippy::unnecessary_wraps
 )]
 
-mod gfx;
-
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use log::*;
-use vulkanalia::prelude::v1_0::*;
-use vulkanalia::Version;
+use vulkanalia::vk;
 use winit::dpi::LogicalSize;
-use winit::event::{Event, WindowEvent};
+use winit::event::{ElementState, Event, KeyEvent, WindowEvent};
 use winit::event_loop::EventLoop;
+use winit::keyboard::{KeyCode, PhysicalKey};
 use winit::window::WindowBuilder;
 
-use gfx::*;
-use gfx::app::App;
-
-const PORTABILITY_MACOS_VERSION: Version = Version::new(1, 3, 216);
-const VALIDATION_ENABLED: bool = cfg!(debug_assertions);
-const VALIDATION_LAYER: vk::ExtensionName =
-    vk::ExtensionName::from_bytes(b"VK_LAYER_KHRONOS_validation");
-const DEVICE_EXTENSIONS: &[vk::ExtensionName] = &[vk::KHR_SWAPCHAIN_EXTENSION.name];
-const MAX_FRAMES_IN_FLIGHT: usize = 2;
+use vk_test::gfx::app::App;
+use vk_test::gfx::camera::set_cursor_grab;
+use vk_test::gfx::pipeline_cache::default_pipeline_cache_path;
+use vk_test::gfx::AppOptions;
 
 fn main() -> Result<()> {
     pretty_env_logger::init();
 
+    let args = std::env::args().collect::<Vec<_>>();
+    let model = arg_value(&args, "--model");
+    let options = parse_options(&args)?;
+    if args.iter().any(|a| a == "--list-devices") {
+        for device in unsafe { App::list_devices(options)? } {
+            println!("{}", device);
+        }
+        return Ok(());
+    }
+    if args.iter().any(|a| a == "--diagnose") {
+        return diagnose(args.iter().any(|a| a == "--headless"), options);
+    }
+    if args.iter().any(|a| a == "--headless") {
+        let output = arg_value(&args, "--headless").unwrap_or("frame.png");
+        return run_headless(output, model, options);
+    }
+
     // Window
 
     let event_loop = EventLoop::new()?;
@@ -39,34 +49,71 @@ fn main() -> Result<()> {
 
     // App
 
-    let mut app = unsafe { App::create(&window)? };
+    let mut app = unsafe { App::create(&window, options)? };
+    if let Some(model) = model {
+        unsafe { app.load_model(model)? };
+    }
+    // Taken out and dropped on close, so the app is destroyed before the window.
+    let mut app = Some(app);
     let mut minimized = false;
+    let mut grabbed = false;
     event_loop.run(move |event, elwt| {
         match event {
             // Request a redraw when all events were processed.
             Event::AboutToWait => window.request_redraw(),
-            Event::WindowEvent { event, .. } => match event {
-                // Render a frame if our Vulkan app is not being destroyed.
-                WindowEvent::RedrawRequested if !elwt.exiting() && !minimized => {
-                    unsafe { app.render(&window) }.unwrap()
+            Event::DeviceEvent { event, .. } => {
+                if let Some(app) = &mut app {
+                    app.camera.handle_device_event(&event);
                 }
-                WindowEvent::Resized(size) => {
-                    if size.width == 0 || size.height == 0 {
-                        minimized = true;
-                    } else {
-                        minimized = false;
-                        app.resized = true;
+            }
+            Event::WindowEvent { event, .. } => {
+                if let Some(app) = &mut app {
+                    app.camera.handle_window_event(&event);
+                    if app.camera.cursor_grabbed() != grabbed {
+                        grabbed = app.camera.cursor_grabbed();
+                        set_cursor_grab(&window, grabbed);
                     }
                 }
-                // Destroy our Vulkan app.
-                WindowEvent::CloseRequested => {
-                    elwt.exit();
-                    unsafe {
-                        app.destroy();
+                match event {
+                    // Render a frame if our Vulkan app is not being destroyed.
+                    WindowEvent::RedrawRequested if !elwt.exiting() && !minimized => {
+                        if let Some(app) = &mut app {
+                            unsafe { app.render(&window) }.unwrap()
+                        }
                     }
+                    // Cycle through the supported present modes.
+                    WindowEvent::KeyboardInput {
+                        event:
+                            KeyEvent {
+                                physical_key: PhysicalKey::Code(KeyCode::KeyV),
+                                state: ElementState::Pressed,
+                                repeat: false,
+                                ..
+                            },
+                        ..
+                    } if !minimized => {
+                        if let Some(app) = &mut app {
+                            unsafe { app.cycle_present_mode(&window) }.unwrap()
+                        }
+                    }
+                    WindowEvent::Resized(size) => {
+                        if size.width == 0 || size.height == 0 {
+                            minimized = true;
+                        } else {
+                            minimized = false;
+                            if let Some(app) = &mut app {
+                                app.resized = true;
+                            }
+                        }
+                    }
+                    // Destroy our Vulkan app.
+                    WindowEvent::CloseRequested => {
+                        elwt.exit();
+                        app = None;
+                    }
+                    _ => {}
                 }
-                _ => {}
-            },
+            }
             Event::LoopExiting => {
                 debug!("exiting loop");
             }
@@ -76,3 +123,126 @@ fn main() -> Result<()> {
 
     Ok(())
 }
+
+/// Returns the value following `flag` on the command line, if any.
+fn arg_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    let index = args.iter().position(|a| a == flag)?;
+    args.get(index + 1)
+        .map(|a| a.as_str())
+        .filter(|a| !a.starts_with("--"))
+}
+
+/// Builds the app options from command line flags.
+fn parse_options(args: &[String]) -> Result<AppOptions> {
+    let mut options = AppOptions::default();
+    options.features.optional.core.sampler_anisotropy = vk::TRUE;
+    options.features.optional.core.fill_mode_non_solid = vk::TRUE;
+    options.features.optional.core.wide_lines = vk::TRUE;
+
+    if let Some(samples) = arg_value(args, "--msaa") {
+        let samples = samples.parse::<u32>()?;
+        options.msaa_samples = Some(
+            vk::SampleCountFlags::from_bits(samples)
+                .filter(|_| samples.is_power_of_two())
+                .ok_or_else(|| anyhow!("Invalid MSAA sample count `{}`.", samples))?,
+        );
+    }
+
+    if args.iter().any(|a| a == "--hot-reload") {
+        let dir = arg_value(args, "--hot-reload").unwrap_or("shaders");
+        options.shader_dir = Some(dir.into());
+    }
+
+    // The pipeline cache lives in the per-user cache directory unless given a
+    // path, so runs don't leave files in the working directory.
+    if !args.iter().any(|a| a == "--no-pipeline-cache") {
+        options.pipeline_cache_path = match arg_value(args, "--pipeline-cache") {
+            Some(path) => Some(path.into()),
+            None => default_pipeline_cache_path(),
+        };
+    }
+
+    // The command line takes precedence over the environment. A device is
+    // picked by index (`#1`), UUID or part of its name (`3080`).
+    let device = arg_value(args, "--device")
+        .map(String::from)
+        .or_else(|| std::env::var("VK_TEST_DEVICE").ok());
+    if let Some(device) = device {
+        options.device = Some(device.parse()?);
+    }
+
+    let validation = arg_value(args, "--validation")
+        .map(String::from)
+        .or_else(|| std::env::var("VK_TEST_VALIDATION").ok());
+    if let Some(validation) = validation {
+        options.validation = validation.parse()?;
+    }
+
+    let severity = arg_value(args, "--validation-severity")
+        .map(String::from)
+        .or_else(|| std::env::var("VK_TEST_VALIDATION_SEVERITY").ok());
+    if let Some(severity) = severity {
+        options.validation.set_min_severity(&severity)?;
+    }
+
+    let types = arg_value(args, "--validation-types")
+        .map(String::from)
+        .or_else(|| std::env::var("VK_TEST_VALIDATION_TYPES").ok());
+    if let Some(types) = types {
+        options.validation.set_message_types(&types)?;
+    }
+
+    let allowlist = arg_value(args, "--validation-allowlist")
+        .map(String::from)
+        .or_else(|| std::env::var("VK_TEST_VALIDATION_ALLOWLIST").ok());
+    if let Some(allowlist) = allowlist {
+        options.validation.allowlist = Some(allowlist.into());
+    }
+
+    if let Some(modes) = arg_value(args, "--present-mode") {
+        options.present_modes = modes
+            .split(',')
+            .map(str::parse)
+            .collect::<Result<_>>()?;
+    }
+
+    if let Some(fraction) = arg_value(args, "--sample-shading") {
+        options.min_sample_shading = Some(fraction.parse()?);
+    }
+
+    Ok(options)
+}
+
+/// Prints the suitability report of every physical device, checking
+/// presentation to a hidden window unless `headless`.
+fn diagnose(headless: bool, options: AppOptions) -> Result<()> {
+    let reports = if headless {
+        unsafe { App::diagnose(None, options)? }
+    } else {
+        let event_loop = EventLoop::new()?;
+        let window = WindowBuilder::new().with_visible(false).build(&event_loop)?;
+        unsafe { App::diagnose(Some(&window), options)? }
+    };
+
+    for report in reports {
+        print!("{}", report);
+    }
+
+    Ok(())
+}
+
+/// Renders a single frame without a window and writes it to `output`.
+fn run_headless(output: &str, model: Option<&str>, options: AppOptions) -> Result<()> {
+    let mut app = unsafe { App::create_headless(1024, 768, options)? };
+
+    let frame = unsafe {
+        model.map_or(Ok(()), |m| app.load_model(m))?;
+        app.render_headless()?;
+        app.read_frame()?
+    };
+
+    frame.save_png(output)?;
+    info!("Wrote headless frame to `{}`.", output);
+
+    Ok(())
+}
@@ -0,0 +1,86 @@
+use std::path::Path;
+
+use vk_test::gfx::app::App;
+use vk_test::gfx::golden::*;
+use vk_test::gfx::offscreen::Frame;
+use vk_test::gfx::validation::ValidationOptions;
+use vk_test::gfx::AppOptions;
+use vulkanalia::vk;
+
+const WIDTH: u32 = 256;
+const HEIGHT: u32 = 256;
+
+/// Fixed settings, so the reference images don't depend on the device's
+/// maximum sample count or the build profile. Every device supports 4x MSAA.
+fn scene_options() -> AppOptions {
+    AppOptions {
+        msaa_samples: Some(vk::SampleCountFlags::_4),
+        validation: ValidationOptions {
+            enabled: true,
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+/// Renders `frames` frames of the default scene and checks the last one
+/// against `tests/golden/<name>.png`.
+///
+/// Scene tests are ignored by default, since they need a Vulkan device; run
+/// them with `cargo test -- --ignored`. Only the last frame is compared.
+///
+/// To create or update a reference image after an intended change, run
+///
+/// ```text
+/// VK_TEST_BLESS=1 cargo test --test golden -- --ignored
+/// ```
+///
+/// and commit the images written to `tests/golden/`.
+fn check_scene(name: &str, frames: u32, time_step: f32, tolerance: &Tolerance) {
+    assert!(
+        vulkan_device_available(),
+        "Golden test `{}` needs a Vulkan device, but none was found.",
+        name
+    );
+
+    let frame = unsafe {
+        let mut app = App::create_headless(WIDTH, HEIGHT, scene_options()).unwrap();
+        let frame = render_frames(&mut app, frames, time_step).unwrap();
+        app.validation_messages().assert_clean().unwrap();
+        frame
+    };
+
+    let golden_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    let output_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("golden");
+    check_golden(name, &frame, &golden_dir, &output_dir, tolerance).unwrap();
+}
+
+#[test]
+#[ignore = "needs a Vulkan device"]
+fn spinning_quad() {
+    // A quarter second into the spin, the quad has turned 22.5 degrees.
+    check_scene("spinning_quad", 16, 1.0 / 60.0, &Tolerance::default());
+}
+
+#[test]
+fn compare_tolerates_small_differences() {
+    let expected = Frame {
+        width: 2,
+        height: 1,
+        pixels: vec![10, 20, 30, 255, 200, 200, 200, 255],
+    };
+    let mut actual = expected.clone();
+    actual.pixels[0] = 12;
+
+    let tolerance = Tolerance {
+        max_mismatched: 0.0,
+        ..Default::default()
+    };
+    assert!(compare(&actual, &expected, &tolerance).unwrap().passes(&tolerance));
+
+    actual.pixels[4] = 0;
+    let comparison = compare(&actual, &expected, &tolerance).unwrap();
+    assert_eq!(comparison.mismatched, 1);
+    assert_eq!(&comparison.diff.pixels[4..8], &[255, 0, 0, 255]);
+    assert!(!comparison.passes(&tolerance));
+}